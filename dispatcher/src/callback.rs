@@ -0,0 +1,213 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::data::{AwaitingCallback, DynamicTaskMessage};
+use crate::mq::MessageQueue;
+use crate::producer::Producer;
+
+/// Value of a callback that has arrived
+const DONE: &str = "done";
+/// Value of a callback that didn't arrive in time
+const TIMED_OUT: &str = "timed-out";
+
+/// Keeps track of the callbacks that tasks of asynchronous handlers are waiting on.
+///
+/// Each pending callback is a value shared through the message queue, holding the task and
+/// the callback's lease, so any Dispatcher can take the callback or a heartbeat. Whichever of
+/// the callback and its timeout comes first settles it with compare-and-set, and then goes
+/// through the queue as a message to the task's topic: the task with the worker's response, or
+/// the task with none, published for when the lease runs out and again whenever a heartbeat
+/// has extended it by then.
+#[derive(Clone)]
+pub struct Callbacks {
+    mq: Arc<dyn MessageQueue>,
+    producer: Producer,
+}
+
+impl Callbacks {
+    pub fn new(mq: Arc<dyn MessageQueue>) -> Callbacks {
+        let producer = Producer::new(mq.clone());
+        Callbacks { mq, producer }
+    }
+
+    fn key(token: &str) -> String {
+        format!("callback.{}", token)
+    }
+
+    /// Reads a pending callback's value, if it's pending
+    async fn pending(&self, key: &str) -> Result<Option<(String, Lease)>> {
+        match self.mq.get_value(key).await? {
+            Some(value) if value != DONE && value != TIMED_OUT => {
+                let lease = serde_json::from_str(&value)?;
+                Ok(Some((value, lease)))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Records that `task` waits on its callback, for up to `timeout` unless a heartbeat
+    /// extends that, returning when it times out. Done before the worker is called, since it
+    /// may call back before it has responded.
+    pub async fn register(&self, task: &DynamicTaskMessage, callback: &AwaitingCallback, timeout: Duration) -> Result<SystemTime> {
+        let lease = Lease::new(task.clone(), timeout);
+        let until = lease.until;
+        if !self.mq.compare_and_set(&Self::key(&callback.token), None, &serde_json::to_string(&lease)?).await? {
+            bail!("callback token {} is already in use", callback.token);
+        }
+        Ok(until)
+    }
+
+    /// Schedules the check for whether the worker called back in time
+    pub async fn expect(&self, task: &DynamicTaskMessage, timeout_at: SystemTime) -> Result<()> {
+        self.producer.send_at(task, timeout_at).await
+    }
+
+    /// Forgets a callback that the worker didn't ask for. Failures are only logged, since
+    /// nothing waits on the callback.
+    pub async fn cancel(&self, callback: &AwaitingCallback) {
+        if let Err(e) = self.mq.delete_value(&Self::key(&callback.token)).await {
+            log::warn!("callback:<{}> could not be cancelled: {:?}", callback.token, e);
+        }
+    }
+
+    /// Completes a callback with the worker's response, publishing the task with the response.
+    /// Returns false if there's no such callback pending, because it's unknown, has already
+    /// arrived or has timed out.
+    pub async fn complete(&self, token: &str, response: Value) -> Result<bool> {
+        let key = Self::key(token);
+        loop {
+            let Some((pending, lease)) = self.pending(&key).await? else {
+                return Ok(false);
+            };
+            if !self.mq.compare_and_set(&key, Some(&pending), DONE).await? {
+                // Changed in between, by a heartbeat or another response
+                continue;
+            }
+            let task = DynamicTaskMessage {
+                callback: Some(AwaitingCallback { token: token.to_owned(), response: Some(response) }),
+                ..lease.task
+            };
+            if let Err(e) = self.producer.send(&task).await {
+                // Reopen the callback so the worker can try again
+                self.mq.compare_and_set(&key, Some(DONE), &pending).await?;
+                return Err(e);
+            }
+            return Ok(true);
+        }
+    }
+
+    /// Extends a pending callback's lease, returning when it now times out, or None if there's
+    /// no such callback pending
+    pub async fn heartbeat(&self, token: &str) -> Result<Option<SystemTime>> {
+        let key = Self::key(token);
+        loop {
+            let Some((pending, lease)) = self.pending(&key).await? else {
+                return Ok(None);
+            };
+            let lease = Lease::new(lease.task, lease.timeout);
+            if self.mq.compare_and_set(&key, Some(&pending), &serde_json::to_string(&lease)?).await? {
+                return Ok(Some(lease.until));
+            }
+        }
+    }
+
+    /// At a callback's timeout, whether it timed out rather than having arrived, in which case
+    /// the callback is forgotten. If heartbeats have extended its lease the check is scheduled
+    /// again instead. A callback that's unknown counts as timed out, since its value may have
+    /// been lost, and the task is better attempted again than dropped.
+    pub async fn timed_out(&self, callback: &AwaitingCallback) -> Result<bool> {
+        let key = Self::key(&callback.token);
+        loop {
+            let Some((pending, lease)) = self.pending(&key).await? else {
+                return match self.mq.get_value(&key).await?.as_deref() {
+                    Some(DONE) => {
+                        self.mq.delete_value(&key).await?;
+                        Ok(false)
+                    },
+                    // Another check of the same timeout got there first
+                    Some(_) => Ok(false),
+                    None => {
+                        log::warn!("callback:<{}> is unknown at its timeout, so it counts as timed out", callback.token);
+                        Ok(true)
+                    },
+                };
+            };
+            if lease.until > SystemTime::now() {
+                self.expect(&lease.task, lease.until).await?;
+                return Ok(false);
+            }
+            if self.mq.compare_and_set(&key, Some(&pending), TIMED_OUT).await? {
+                self.mq.delete_value(&key).await?;
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// A pending callback's value
+#[derive(Serialize, Deserialize)]
+struct Lease {
+    task: DynamicTaskMessage,
+    /// How long each heartbeat extends the lease by
+    #[serde(with = "humantime_serde")]
+    timeout: Duration,
+    /// When the callback times out, unless a heartbeat extends the lease
+    #[serde(with = "humantime_serde")]
+    until: SystemTime,
+}
+
+impl Lease {
+    /// A lease running for `timeout` from now, but not beyond the task's deadline
+    fn new(task: DynamicTaskMessage, timeout: Duration) -> Lease {
+        let timeout_at = SystemTime::now() + timeout;
+        let until = task.expires_at().map_or(timeout_at, |expires_at| expires_at.min(timeout_at));
+        Lease { task, timeout, until }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::data::{AwaitingCallback, DynamicTaskMessage};
+    use crate::mq::{MemoryQueue, MessageQueue};
+
+    use super::Callbacks;
+
+    async fn registered(timeout: Duration) -> (Arc<MemoryQueue>, Callbacks, AwaitingCallback) {
+        let mq = Arc::new(MemoryQueue::new(&["task".to_owned()]));
+        let callbacks = Callbacks::new(mq.clone());
+        let callback = AwaitingCallback::issue();
+        let task = DynamicTaskMessage::new("task".to_owned(), json!({}));
+        callbacks.register(&task, &callback, timeout).await.unwrap();
+        (mq, callbacks, callback)
+    }
+
+    #[tokio::test]
+    async fn arrived_callback_isnt_timed_out() {
+        let (_, callbacks, callback) = registered(Duration::ZERO).await;
+        assert!(callbacks.complete(&callback.token, json!({"tasks": []})).await.unwrap());
+        assert!(!callbacks.timed_out(&callback).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_lease_times_out_once() {
+        let (_, callbacks, callback) = registered(Duration::ZERO).await;
+        assert!(callbacks.timed_out(&callback).await.unwrap());
+        assert!(!callbacks.complete(&callback.token, json!({"tasks": []})).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn lost_callback_counts_as_timed_out() {
+        let (mq, callbacks, callback) = registered(Duration::from_secs(60)).await;
+        mq.delete_value(&Callbacks::key(&callback.token)).await.unwrap();
+        assert!(callbacks.timed_out(&callback).await.unwrap());
+    }
+}
@@ -1,11 +1,14 @@
+use std::collections::HashMap;
 use std::fs;
+use std::time::SystemTime;
 use serde::Deserialize;
-use toml;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
 #[derive(Deserialize, Clone)]
 pub struct Config {
     pub mq: Mq,
+    #[serde(default)]
+    pub dispatch: Dispatch,
 
     // FUTURE:
     // topics
@@ -18,19 +21,556 @@ pub struct Config {
     // task publish rules
 
     pub handlers: Vec<TaskHandler>,
+    #[serde(default)]
+    pub schedules: Vec<Schedule>,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct Mq {
-    pub url: String,
-    pub topics: Vec<String>
+    #[serde(default)]
+    pub backend: Backend,
+    pub url: Option<String>,
+    pub topics: Vec<String>,
+    /// Topic that receives tasks that can't be processed. If unset, such tasks are dropped.
+    pub dead_letter_topic: Option<String>,
+    /// Topic that receives tasks whose deadline passed before they were handled. If unset,
+    /// such tasks are dropped.
+    pub expired_topic: Option<String>,
+    #[serde(default)]
+    pub redis: Redis,
+    #[serde(default)]
+    pub postgres: Postgres,
+    #[serde(default)]
+    pub nats: Nats,
+    #[serde(default)]
+    pub sqlite: Sqlite,
+}
+
+/// Which message queue implementation the Dispatcher uses
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    #[default]
+    Pulsar,
+    /// In-process queue that doesn't survive restarts, for development and tests
+    Memory,
+    /// Redis Streams, with one stream per topic
+    Redis,
+    /// A PostgreSQL table, so applications can enqueue tasks transactionally
+    Postgres,
+    /// NATS JetStream, with one subject per topic read through a durable pull consumer
+    Nats,
+    /// A table in an SQLite file, so the Dispatcher can run without a broker
+    Sqlite,
+}
+
+/// Options for the redis backend, in an `[mq.redis]` section
+#[derive(Deserialize, Clone)]
+pub struct Redis {
+    #[serde(default = "Redis::default_group")]
+    pub group: String,
+    /// Must be unique among Dispatchers sharing the group. Defaults to `$HOSTNAME`, which is the pod name on Kubernetes.
+    #[serde(default = "Redis::default_consumer")]
+    pub consumer: String,
+    /// How long a message can go unacked before another Dispatcher claims it
+    #[serde(default = "Redis::default_claim_idle_ms")]
+    pub claim_idle_ms: u64,
+    /// How often delayed messages are checked for being due
+    #[serde(default = "Redis::default_delayed_poll_ms")]
+    pub delayed_poll_ms: u64,
+}
+
+impl Redis {
+    fn default_group() -> String { "trampoline-dispatch".to_owned() }
+    fn default_consumer() -> String { std::env::var("HOSTNAME").unwrap_or("trampoline-dispatcher".to_owned()) }
+    fn default_claim_idle_ms() -> u64 { 60_000 }
+    fn default_delayed_poll_ms() -> u64 { 1000 }
+}
+
+impl Default for Redis {
+    fn default() -> Redis {
+        Redis {
+            group: Self::default_group(),
+            consumer: Self::default_consumer(),
+            claim_idle_ms: Self::default_claim_idle_ms(),
+            delayed_poll_ms: Self::default_delayed_poll_ms(),
+        }
+    }
+}
+
+/// Options for the postgres backend, in an `[mq.postgres]` section
+#[derive(Deserialize, Clone)]
+pub struct Postgres {
+    /// Created if it doesn't exist
+    #[serde(default = "Postgres::default_table")]
+    pub table: String,
+    /// How long a received message stays hidden from other Dispatchers before it's redelivered,
+    /// unless it's acked first
+    #[serde(default = "Postgres::default_visibility_timeout_ms")]
+    pub visibility_timeout_ms: u64,
+    /// How long to wait before checking again when no message is available
+    #[serde(default = "Postgres::default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+}
+
+impl Postgres {
+    fn default_table() -> String { "trampoline_tasks".to_owned() }
+    fn default_visibility_timeout_ms() -> u64 { 300_000 }
+    fn default_poll_interval_ms() -> u64 { 500 }
+}
+
+impl Default for Postgres {
+    fn default() -> Postgres {
+        Postgres {
+            table: Self::default_table(),
+            visibility_timeout_ms: Self::default_visibility_timeout_ms(),
+            poll_interval_ms: Self::default_poll_interval_ms(),
+        }
+    }
+}
+
+/// Options for the nats backend, in an `[mq.nats]` section
+#[derive(Deserialize, Clone)]
+pub struct Nats {
+    /// Tasks of a topic are published on the subject `{subject_prefix}.{topic}`
+    #[serde(default = "Nats::default_subject_prefix")]
+    pub subject_prefix: String,
+    /// JetStream stream holding the task subjects, created if it doesn't exist
+    #[serde(default = "Nats::default_stream")]
+    pub stream: String,
+    /// Name of the durable consumer shared by all Dispatchers
+    #[serde(default = "Nats::default_durable")]
+    pub durable: String,
+    /// How long a message can go unacked before it's redelivered
+    #[serde(default = "Nats::default_ack_wait_ms")]
+    pub ack_wait_ms: u64,
+}
+
+impl Nats {
+    fn default_subject_prefix() -> String { "trampoline".to_owned() }
+    fn default_stream() -> String { "TRAMPOLINE".to_owned() }
+    fn default_durable() -> String { "trampoline-dispatch".to_owned() }
+    fn default_ack_wait_ms() -> u64 { 60_000 }
+}
+
+impl Default for Nats {
+    fn default() -> Nats {
+        Nats {
+            subject_prefix: Self::default_subject_prefix(),
+            stream: Self::default_stream(),
+            durable: Self::default_durable(),
+            ack_wait_ms: Self::default_ack_wait_ms(),
+        }
+    }
+}
+
+/// Options for the sqlite backend, in an `[mq.sqlite]` section
+#[derive(Deserialize, Clone)]
+pub struct Sqlite {
+    /// Database file, created if it doesn't exist
+    #[serde(default = "Sqlite::default_path")]
+    pub path: String,
+    /// How long a received message is hidden before it's redelivered, unless it's acked first
+    #[serde(default = "Sqlite::default_visibility_timeout_ms")]
+    pub visibility_timeout_ms: u64,
+    /// How long to wait before checking again when no message is available
+    #[serde(default = "Sqlite::default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+}
+
+impl Sqlite {
+    fn default_path() -> String { "trampoline.db".to_owned() }
+    fn default_visibility_timeout_ms() -> u64 { 300_000 }
+    fn default_poll_interval_ms() -> u64 { 500 }
+}
+
+impl Default for Sqlite {
+    fn default() -> Sqlite {
+        Sqlite {
+            path: Self::default_path(),
+            visibility_timeout_ms: Self::default_visibility_timeout_ms(),
+            poll_interval_ms: Self::default_poll_interval_ms(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Dispatch {
+    /// Maximum number of tasks processed concurrently, across all handlers
+    #[serde(default = "Dispatch::default_max_in_flight")]
+    pub max_in_flight: usize,
+    /// Maximum number of received tasks waiting on their handler's own `max_in_flight` or
+    /// `rate_limit`, besides those in flight. The Dispatcher stops receiving once it holds
+    /// `max_in_flight` and `max_waiting` together.
+    #[serde(default = "Dispatch::default_max_waiting")]
+    pub max_waiting: usize,
+    /// URL at which workers reach the Dispatchers' HTTP server, which asynchronous handlers'
+    /// callback URLs start with. Any Dispatcher using the same queue can take a callback.
+    pub callback_url: Option<String>,
+}
+
+impl Dispatch {
+    fn default_max_in_flight() -> usize { 32 }
+    fn default_max_waiting() -> usize { 32 }
+}
+
+impl Default for Dispatch {
+    fn default() -> Dispatch {
+        Dispatch { max_in_flight: Self::default_max_in_flight(), max_waiting: Self::default_max_waiting(), callback_url: None }
+    }
 }
 
 #[derive(Deserialize, Clone)]
 pub struct TaskHandler {
     pub task_selector: Select,
     pub endpoint: Option<String>,
+    /// Replicas of the same worker that tasks are spread across, instead of a single `endpoint`
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+    /// How tasks are spread across `endpoints`, in a `[handlers.balancing]` section
+    #[serde(default)]
+    pub balancing: Balancing,
     pub pipeline: Option<String>,
+    /// A local program and its arguments, run for each task instead of calling an endpoint
+    pub command: Option<Vec<String>>,
+    /// How a `command` is run, in a `[handlers.exec]` section
+    #[serde(default)]
+    pub exec: Exec,
+    #[serde(default)]
+    pub delivery: Delivery,
+    pub retry: Option<Retry>,
+    /// Treat a response that can't be parsed as a WorkerResponse as a failure that goes to
+    /// the dead-letter topic, rather than continuing without new tasks
+    #[serde(default)]
+    pub strict: bool,
+    /// Maximum number of this handler's tasks processed concurrently. Tasks waiting on this
+    /// limit count towards `dispatch.max_waiting` rather than `dispatch.max_in_flight`.
+    pub max_in_flight: Option<usize>,
+    /// How an `endpoint` is called, in a `[handlers.http]` section
+    #[serde(default)]
+    pub http: Http,
+    /// Makes the handler asynchronous: its worker may accept a task with 202 and respond
+    /// later through a callback
+    pub callback: Option<Callback>,
+    /// Sends the handler's tasks to its worker several at a time, in a `[handlers.batch]`
+    /// section
+    pub batch: Option<Batch>,
+    /// Stops calling the handler's endpoints while they're failing, in a
+    /// `[handlers.circuit_breaker]` section
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Limits how fast the handler's tasks are sent, in a `[handlers.rate_limit]` section
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// A token bucket, from which each task sent takes a token
+#[derive(Deserialize, Clone)]
+pub struct RateLimit {
+    /// Tokens added to the bucket per second
+    pub per_second: f64,
+    /// Most tokens the bucket holds, and so the most tasks sent at once after a quiet spell.
+    /// Defaults to a second's worth.
+    pub burst: Option<u32>,
+    /// Shares the bucket among all Dispatchers using the queue, through its shared values
+    #[serde(default)]
+    pub shared: bool,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CircuitBreaker {
+    /// Consecutive failures, which are failed requests and 5xx responses, that open the breaker
+    #[serde(default = "CircuitBreaker::default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// How long the breaker stays open before it lets trial calls through
+    #[serde(default = "CircuitBreaker::default_open_ms")]
+    pub open_ms: u64,
+    /// Most trial calls in flight while half-open. The first to succeed closes the breaker,
+    /// and any failure opens it again.
+    #[serde(default = "CircuitBreaker::default_half_open_calls")]
+    pub half_open_calls: u32,
+}
+
+impl CircuitBreaker {
+    fn default_failure_threshold() -> u32 { 5 }
+
+    fn default_open_ms() -> u64 { 30_000 }
+
+    fn default_half_open_calls() -> u32 { 1 }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Endpoint {
+    pub url: String,
+    /// Share of tasks relative to the other endpoints, under the `weighted` strategy
+    #[serde(default = "Endpoint::default_weight")]
+    pub weight: u32,
+}
+
+impl Endpoint {
+    fn default_weight() -> u32 { 1 }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Balancing {
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// Checks each endpoint periodically, taking it out of rotation while it's failing
+    pub health_check: Option<HealthCheck>,
+    /// Ejects an endpoint after this many consecutive errors, which are failed requests and
+    /// 5xx responses
+    pub eject_after_errors: Option<u32>,
+    /// How long an ejected endpoint is out of rotation
+    #[serde(default = "Balancing::default_eject_ms")]
+    pub eject_ms: u64,
+}
+
+impl Balancing {
+    fn default_eject_ms() -> u64 { 30_000 }
+}
+
+impl Default for Balancing {
+    fn default() -> Self {
+        Balancing { strategy: Strategy::default(), health_check: None, eject_after_errors: None, eject_ms: Self::default_eject_ms() }
+    }
+}
+
+/// How the endpoint for each task is selected
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Each endpoint in turn
+    #[default]
+    RoundRobin,
+    /// The endpoint with the fewest requests in flight
+    LeastInFlight,
+    /// An endpoint chosen at random, in proportion to its `weight`
+    Weighted,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HealthCheck {
+    /// Path requested with GET on each endpoint's host, which is healthy if it responds with 2xx
+    pub path: String,
+    #[serde(default = "HealthCheck::default_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default = "HealthCheck::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl HealthCheck {
+    fn default_interval_ms() -> u64 { 10_000 }
+
+    fn default_timeout_ms() -> u64 { 2_000 }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Batch {
+    /// Most tasks sent in one request
+    #[serde(default = "Batch::default_max_size")]
+    pub max_size: usize,
+    /// Longest the first task of a batch waits for others to join it before the batch is sent
+    #[serde(default = "Batch::default_max_wait_ms")]
+    pub max_wait_ms: u64,
+}
+
+impl Batch {
+    fn default_max_size() -> usize { 10 }
+
+    fn default_max_wait_ms() -> u64 { 100 }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Callback {
+    /// How long the worker has to call back once it has been sent a task, or since its last
+    /// heartbeat, after which the attempt has failed
+    #[serde(default = "Callback::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl Callback {
+    fn default_timeout_ms() -> u64 { 3_600_000 }
+}
+
+/// Settings for running a handler's command
+#[derive(Deserialize, Clone)]
+pub struct Exec {
+    /// How long the command has for each task. A task's deadline shortens it further.
+    pub timeout_ms: Option<u64>,
+    /// Set in the command's environment, in addition to the Dispatcher's own
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    pub working_dir: Option<String>,
+    /// Exit codes that mean the task should be retried. Other non-zero codes fail it.
+    #[serde(default = "Exec::default_retry_exit_codes")]
+    pub retry_exit_codes: Vec<i32>,
+    /// Keeps up to this many processes running, each handling one task at a time, rather
+    /// than starting a process per task
+    pub pool_size: Option<usize>,
+}
+
+impl Exec {
+    /// EX_TEMPFAIL
+    fn default_retry_exit_codes() -> Vec<i32> { vec![75] }
+}
+
+impl Default for Exec {
+    fn default() -> Self {
+        Exec { timeout_ms: None, env: HashMap::new(), working_dir: None, retry_exit_codes: Self::default_retry_exit_codes(), pool_size: None }
+    }
+}
+
+/// HTTP settings for calling a handler's endpoint
+#[derive(Deserialize, Clone, Default)]
+pub struct Http {
+    /// Request timeout. A task's deadline shortens it further.
+    pub timeout_ms: Option<u64>,
+    /// Sent with every request
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Sent as `Authorization: Bearer <token>`
+    pub bearer_token: Option<Secret>,
+    pub basic_auth: Option<BasicAuth>,
+    pub tls: Option<Tls>,
+    /// Signs each request's body so the worker can verify it came from the Dispatcher
+    pub signing: Option<Signing>,
+    #[serde(default)]
+    pub body: Body,
+}
+
+/// What a request to a worker carries as its body
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Body {
+    /// Just the task, with its metadata in headers
+    #[default]
+    Task,
+    /// The task's whole envelope, as it travels through the message queue
+    Envelope,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: Secret,
+}
+
+/// TLS settings for mutual TLS with an endpoint
+#[derive(Deserialize, Clone)]
+pub struct Tls {
+    /// PEM file with the client certificate, optionally followed by its chain
+    pub client_cert: String,
+    /// PEM file with the client certificate's PKCS#8 private key
+    pub client_key: String,
+    /// PEM file with a CA certificate to trust in addition to the system's
+    pub ca_cert: Option<String>,
+}
+
+/// HMAC-SHA256 request signing. Requests are signed with the most recently activated of
+/// `keys`, so a new key can be rolled out to workers before the Dispatcher switches to it.
+#[derive(Deserialize, Clone)]
+pub struct Signing {
+    pub keys: Vec<SigningKey>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SigningKey {
+    /// Sent with each signature, so the worker knows which key to verify it with
+    pub id: String,
+    pub secret: Secret,
+    /// When the Dispatcher starts signing with this key, given as an RFC 3339 timestamp.
+    /// Keys without one are active from the start.
+    #[serde(default, with = "humantime_serde")]
+    pub active_from: Option<SystemTime>,
+}
+
+/// A secret, read at startup from an environment variable or a file so that it never has to
+/// appear in the config, e.g. `{ env = "WORKER_TOKEN" }` or `{ file = "/var/run/secrets/token" }`
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum Secret {
+    Env(String),
+    File(String),
+}
+
+impl Secret {
+    /// Reads the secret, without surrounding whitespace such as a file's trailing newline
+    pub fn read(&self) -> Result<String> {
+        let secret = match self {
+            Secret::Env(name) => std::env::var(name).context(format!("reading secret from environment variable `{}` failed", name))?,
+            Secret::File(path) => fs::read_to_string(path).context(format!("reading secret from file `{}` failed", path))?,
+        };
+        Ok(secret.trim().to_owned())
+    }
+}
+
+/// When a task's message is acknowledged relative to handling it
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Delivery {
+    /// Ack as soon as the message is received. A crash or handler failure loses the task.
+    #[default]
+    AtMostOnce,
+
+    /// Ack only after the handler succeeds and every resulting task has been confirmed
+    /// by the producer. Failures are negatively acknowledged so the message is redelivered.
+    AtLeastOnce,
+}
+
+/// Retry policy for a handler, configured in a `[handlers.retry]` section
+#[derive(Deserialize, Clone)]
+pub struct Retry {
+    /// Total number of attempts, including the first
+    #[serde(default = "Retry::default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "Retry::default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "Retry::default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// Fraction of each backoff that is randomized, between 0.0 and 1.0
+    #[serde(default = "Retry::default_jitter")]
+    pub jitter: f64,
+    /// HTTP statuses from a worker that are retried, besides 429 and 5xx which always are
+    #[serde(default)]
+    pub retryable_statuses: Vec<u16>,
+}
+
+impl Retry {
+    fn default_max_attempts() -> u32 { 3 }
+    fn default_initial_backoff_ms() -> u64 { 1000 }
+    fn default_max_backoff_ms() -> u64 { 60_000 }
+    fn default_jitter() -> f64 { 0.2 }
+}
+
+/// A task published on a recurring schedule, from a `[[schedules]]` section
+#[derive(Deserialize, Clone)]
+pub struct Schedule {
+    /// Identifies the schedule across Dispatchers and restarts, so it must be unique and should
+    /// stay the same. Letters, digits, `-` and `_` only.
+    pub name: String,
+    /// Cron expression, evaluated in UTC, either with five fields or with seconds as a sixth first field
+    pub cron: String,
+    #[serde(rename = "type")]
+    pub type_name: String,
+    #[serde(default = "Schedule::default_task")]
+    pub task: serde_json::Value,
+    #[serde(default)]
+    pub catch_up: CatchUp,
+}
+
+impl Schedule {
+    fn default_task() -> serde_json::Value { serde_json::json!({}) }
+}
+
+/// What happens to the ticks of a schedule that were missed while no Dispatcher was running
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum CatchUp {
+    /// Missed ticks are skipped
+    None,
+    /// The most recent missed tick runs once
+    #[default]
+    Latest,
+    /// Every missed tick runs, oldest first
+    All,
 }
 
 #[derive(Deserialize, Clone)]
@@ -44,6 +584,9 @@ impl Config {
         let filename = "dispatcher.toml";
         let toml_str = fs::read_to_string(filename).context(format!("Unable to open config file `{}`", filename))?;
         let config: Config = toml::from_str(&toml_str).context(format!("Unable to parse TOML from `{}`", filename))?;
+        if config.dispatch.max_in_flight == 0 {
+            bail!("`dispatch.max_in_flight` must be at least 1");
+        }
         Ok(config)
     }
 }
\ No newline at end of file
@@ -1,4 +1,25 @@
+#[allow(clippy::module_inception)]
 mod config;
 
 pub use config::Config;
-pub use config::TaskHandler;
\ No newline at end of file
+pub use config::Mq;
+pub use config::Backend;
+pub use config::Redis;
+pub use config::Postgres;
+pub use config::Nats;
+pub use config::Sqlite;
+pub use config::TaskHandler;
+pub use config::Http;
+pub use config::Exec;
+pub use config::Body;
+pub use config::Signing;
+pub use config::Batch;
+pub use config::CircuitBreaker;
+pub use config::RateLimit;
+pub use config::Balancing;
+pub use config::Strategy;
+pub use config::HealthCheck;
+pub use config::Delivery;
+pub use config::Retry;
+pub use config::Schedule;
+pub use config::CatchUp;
\ No newline at end of file
@@ -0,0 +1,160 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::seq::SliceRandom;
+use reqwest::Client;
+
+use crate::config::{Balancing, HealthCheck, Strategy};
+use crate::data::DynamicTaskMessage;
+
+use super::handler::{HandleResult, Handler};
+use super::worker::Worker;
+
+/// One of a balanced handler's endpoints, along with what's known about its health
+struct Upstream {
+    worker: Worker,
+    weight: u32,
+    in_flight: AtomicUsize,
+    /// Consecutive errors, for passive ejection
+    errors: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    /// Whether the last active health check passed
+    healthy: AtomicBool,
+}
+
+impl Upstream {
+    fn is_available(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+            && !self.worker.is_circuit_open()
+            && self.ejected_until.lock().unwrap().is_none_or(|until| until <= Instant::now())
+    }
+}
+
+/// Counts a request in flight to an upstream while it's alive
+struct InFlight<'a>(&'a Upstream);
+
+impl<'a> InFlight<'a> {
+    fn start(upstream: &'a Upstream) -> InFlight<'a> {
+        upstream.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(upstream)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Spreads a handler's tasks across several endpoints of the same worker. Endpoints failing
+/// their health checks, ejected after too many consecutive errors, or whose circuit breakers
+/// are open, are left out of rotation, unless all of them are, in which case all are used
+/// rather than failing every task.
+pub struct Balancer {
+    upstreams: Arc<Vec<Upstream>>,
+    strategy: Strategy,
+    /// Round-robin position
+    next: AtomicUsize,
+    eject_after_errors: Option<u32>,
+    eject_for: Duration,
+}
+
+impl Balancer {
+    /// Starts health checks of the workers, if `balancing` has them, sent with `client`
+    pub fn new(workers: Vec<(Worker, u32)>, balancing: &Balancing, client: &Client) -> Balancer {
+        let upstreams = Arc::new(workers.into_iter()
+            .map(|(worker, weight)| Upstream {
+                worker,
+                weight,
+                in_flight: AtomicUsize::new(0),
+                errors: AtomicU32::new(0),
+                ejected_until: Mutex::new(None),
+                healthy: AtomicBool::new(true),
+            })
+            .collect::<Vec<_>>());
+        if let Some(health_check) = &balancing.health_check {
+            tokio::spawn(Self::check_health(Arc::downgrade(&upstreams), client.clone(), health_check.clone()));
+        }
+        Balancer {
+            upstreams,
+            strategy: balancing.strategy,
+            next: AtomicUsize::new(0),
+            eject_after_errors: balancing.eject_after_errors,
+            eject_for: Duration::from_millis(balancing.eject_ms),
+        }
+    }
+
+    /// Checks every endpoint each interval, for as long as the Balancer is alive
+    async fn check_health(upstreams: Weak<Vec<Upstream>>, client: Client, health_check: HealthCheck) {
+        let mut interval = tokio::time::interval(Duration::from_millis(health_check.interval_ms));
+        let timeout = Duration::from_millis(health_check.timeout_ms);
+        loop {
+            interval.tick().await;
+            let Some(upstreams) = upstreams.upgrade() else {
+                return;
+            };
+            let checks = upstreams.iter().map(|upstream| upstream.worker.is_healthy(&client, &health_check.path, timeout));
+            for (upstream, healthy) in upstreams.iter().zip(futures::future::join_all(checks).await) {
+                if upstream.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                    let state = if healthy { "passing" } else { "failing" };
+                    log::warn!("endpoint {} is {} health checks", upstream.worker.redacted_endpoint(), state);
+                }
+            }
+        }
+    }
+
+    /// Selects the endpoint for the next request
+    fn select(&self) -> &Upstream {
+        let available: Vec<&Upstream> = self.upstreams.iter().filter(|upstream| upstream.is_available()).collect();
+        let candidates = if available.is_empty() { self.upstreams.iter().collect() } else { available };
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        match self.strategy {
+            Strategy::RoundRobin => candidates[start % candidates.len()],
+            // Starting from the round-robin position so that ties are spread too
+            Strategy::LeastInFlight => (0..candidates.len())
+                .map(|i| candidates[(start + i) % candidates.len()])
+                .min_by_key(|upstream| upstream.in_flight.load(Ordering::Relaxed))
+                .unwrap(),
+            Strategy::Weighted => candidates
+                .choose_weighted(&mut rand::thread_rng(), |upstream| upstream.weight)
+                .unwrap_or(&candidates[start % candidates.len()]),
+        }
+    }
+
+    /// Counts an error against an endpoint, ejecting it once it has had too many in a row, or
+    /// clears its errors after a request that went fine
+    fn record(&self, upstream: &Upstream, error: bool) {
+        if !error {
+            upstream.errors.store(0, Ordering::Relaxed);
+            return;
+        }
+        let errors = upstream.errors.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.eject_after_errors.is_some_and(|max| errors >= max) {
+            upstream.errors.store(0, Ordering::Relaxed);
+            *upstream.ejected_until.lock().unwrap() = Some(Instant::now() + self.eject_for);
+            log::warn!("endpoint {} ejected for {:?} after {} consecutive errors", upstream.worker.redacted_endpoint(), self.eject_for, errors);
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for Balancer {
+    async fn handle(&self, client: &Client, message_id: &str, task: &DynamicTaskMessage) -> Result<HandleResult> {
+        let upstream = self.select();
+        let _in_flight = InFlight::start(upstream);
+        let result = upstream.worker.handle(client, message_id, task).await;
+        self.record(upstream, result.as_ref().map_or(true, HandleResult::is_server_error));
+        result
+    }
+
+    async fn handle_batch(&self, client: &Client, tasks: &[(&str, &DynamicTaskMessage)]) -> Result<Vec<HandleResult>> {
+        let upstream = self.select();
+        let _in_flight = InFlight::start(upstream);
+        let results = upstream.worker.handle_batch(client, tasks).await;
+        self.record(upstream, results.as_ref().map_or(true, |results| results.iter().any(HandleResult::is_server_error)));
+        results
+    }
+}
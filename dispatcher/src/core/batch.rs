@@ -0,0 +1,76 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use reqwest::Client;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+use crate::config::Batch;
+use crate::data::DynamicTaskMessage;
+
+use super::handler::{HandleResult, Handler};
+
+/// A task waiting for its batch to be sent, and where its result goes
+struct Pending {
+    message_id: String,
+    task: DynamicTaskMessage,
+    result: oneshot::Sender<Result<HandleResult>>,
+}
+
+/// Collects a handler's tasks into batches. A batch is sent once it's full or its first task
+/// has waited `max_wait_ms`, and each task then gets its own result, so it's settled on its
+/// own like any other task.
+pub struct Batcher {
+    pending: mpsc::UnboundedSender<Pending>,
+}
+
+impl Batcher {
+    /// Starts collecting batches for `handler`, which runs until the Batcher is dropped
+    pub fn start(handler: Arc<dyn Handler>, client: Client, config: &Batch) -> Batcher {
+        let (pending, rx) = mpsc::unbounded_channel();
+        tokio::spawn(Self::collect(handler, client, rx, config.max_size, Duration::from_millis(config.max_wait_ms)));
+        Batcher { pending }
+    }
+
+    /// Handles `task` as part of the next batch
+    pub async fn handle(&self, message_id: &str, task: &DynamicTaskMessage) -> Result<HandleResult> {
+        let (result, rx) = oneshot::channel();
+        self.pending.send(Pending { message_id: message_id.to_owned(), task: task.clone(), result })
+            .map_err(|_| anyhow!("batching has stopped"))?;
+        rx.await.map_err(|_| anyhow!("batch ended without a result for the task"))?
+    }
+
+    async fn collect(handler: Arc<dyn Handler>, client: Client, mut rx: mpsc::UnboundedReceiver<Pending>, max_size: usize, max_wait: Duration) {
+        while let Some(first) = rx.recv().await {
+            let send_at = Instant::now() + max_wait;
+            let mut batch = vec![first];
+            while batch.len() < max_size {
+                match tokio::time::timeout_at(send_at, rx.recv()).await {
+                    Ok(Some(pending)) => batch.push(pending),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            // Sent separately so that the next batch is collected meanwhile
+            tokio::spawn(Self::send(handler.clone(), client.clone(), batch));
+        }
+    }
+
+    async fn send(handler: Arc<dyn Handler>, client: Client, batch: Vec<Pending>) {
+        let tasks = batch.iter().map(|pending| (pending.message_id.as_str(), &pending.task)).collect::<Vec<_>>();
+        match handler.handle_batch(&client, &tasks).await {
+            Ok(results) => {
+                for (pending, result) in batch.into_iter().zip(results) {
+                    let _ = pending.result.send(Ok(result));
+                }
+            },
+            Err(e) => {
+                // Each task's attempt failed the same way
+                let reason = format!("{:#}", e);
+                for pending in batch {
+                    let _ = pending.result.send(Err(anyhow!("batch failed: {}", reason)));
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::config::CircuitBreaker;
+
+enum State {
+    /// Calls go through, and consecutive failures are counted
+    Closed { failures: u32 },
+    /// Calls are rejected until the given time
+    Open { until: SystemTime },
+    /// Trial calls go through, up to `half_open_calls` at once
+    HalfOpen { trials: u32 },
+}
+
+/// A circuit breaker for one worker endpoint. After `failure_threshold` consecutive failures it
+/// opens, rejecting calls for `open_ms`, then half-opens to let trial calls through, which
+/// decide whether it closes again.
+pub struct Breaker {
+    task_type: String,
+    endpoint: Url,
+    config: CircuitBreaker,
+    state: Mutex<State>,
+}
+
+/// A call let through by a breaker, whose outcome should be recorded. A trial call dropped
+/// without one, such as when its task is cancelled, gives its place back.
+pub struct Admitted<'a> {
+    breaker: &'a Breaker,
+    trial: bool,
+    recorded: bool,
+}
+
+impl Admitted<'_> {
+    /// Records whether the call failed
+    pub fn record(mut self, failed: bool) {
+        self.recorded = true;
+        self.breaker.record(failed);
+    }
+}
+
+impl Drop for Admitted<'_> {
+    fn drop(&mut self) {
+        if self.trial && !self.recorded {
+            self.breaker.release_trial();
+        }
+    }
+}
+
+/// A breaker's state, as reported by the admin API
+#[derive(Serialize)]
+pub struct BreakerStatus {
+    #[serde(rename = "type")]
+    pub type_name: String,
+    pub endpoint: String,
+    pub state: &'static str,
+    /// When an open breaker half-opens, as an RFC 3339 timestamp
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_until: Option<String>,
+}
+
+impl Breaker {
+    /// A closed breaker for `endpoint`, which should have any password redacted since it's logged
+    pub fn new(task_type: &str, endpoint: Url, config: &CircuitBreaker) -> Breaker {
+        Breaker {
+            task_type: task_type.to_owned(),
+            endpoint,
+            config: config.clone(),
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Lets a call through, or rejects it with when to try again
+    pub fn admit(&self) -> Result<Admitted<'_>, SystemTime> {
+        let now = SystemTime::now();
+        let mut state = self.state.lock().unwrap();
+        let trial = match *state {
+            State::Closed { .. } => false,
+            State::Open { until } if until > now => return Err(self.retry_at(until)),
+            State::Open { .. } => {
+                log::warn!("circuit breaker for {} endpoint {} is half-open", self.task_type, self.endpoint);
+                *state = State::HalfOpen { trials: 1 };
+                true
+            },
+            State::HalfOpen { ref mut trials } if *trials < self.config.half_open_calls => {
+                *trials += 1;
+                true
+            },
+            // Rejected as if the breaker had opened again, since the trials may well fail
+            State::HalfOpen { .. } => return Err(self.retry_at(now)),
+        };
+        Ok(Admitted { breaker: self, trial, recorded: false })
+    }
+
+    /// A random time within `open_ms` of `from`, so that rejected calls don't all come back at once
+    fn retry_at(&self, from: SystemTime) -> SystemTime {
+        from + Duration::from_millis(rand::thread_rng().gen_range(0..=self.config.open_ms))
+    }
+
+    fn record(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        match (&mut *state, failed) {
+            (State::Closed { failures }, false) => *failures = 0,
+            (State::Closed { failures }, true) => {
+                *failures += 1;
+                if *failures >= self.config.failure_threshold {
+                    log::warn!("circuit breaker for {} endpoint {} opened after {} consecutive failures", self.task_type, self.endpoint, failures);
+                    *state = self.open();
+                }
+            },
+            (State::HalfOpen { .. }, false) => {
+                log::warn!("circuit breaker for {} endpoint {} closed", self.task_type, self.endpoint);
+                *state = State::Closed { failures: 0 };
+            },
+            (State::HalfOpen { .. }, true) => {
+                log::warn!("circuit breaker for {} endpoint {} opened again after a failed trial call", self.task_type, self.endpoint);
+                *state = self.open();
+            },
+            // Calls admitted before the breaker opened
+            (State::Open { .. }, _) => {},
+        }
+    }
+
+    /// Gives back a trial call's place, if the breaker is still half-open
+    fn release_trial(&self) {
+        if let State::HalfOpen { trials } = &mut *self.state.lock().unwrap() {
+            *trials = trials.saturating_sub(1);
+        }
+    }
+
+    fn open(&self) -> State {
+        State::Open { until: SystemTime::now() + Duration::from_millis(self.config.open_ms) }
+    }
+
+    /// Whether the breaker is rejecting calls
+    pub fn is_open(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), State::Open { until } if until > SystemTime::now())
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let (state, open_until) = match *self.state.lock().unwrap() {
+            State::Closed { .. } => ("closed", None),
+            State::Open { until } if until > SystemTime::now() => ("open", Some(DateTime::<Utc>::from(until).to_rfc3339())),
+            // Half-opens with the next call
+            State::Open { .. } | State::HalfOpen { .. } => ("half-open", None),
+        };
+        BreakerStatus { type_name: self.task_type.clone(), endpoint: self.endpoint.to_string(), state, open_until }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::config::CircuitBreaker;
+
+    use super::Breaker;
+
+    fn from_config(config: &str) -> Breaker {
+        Breaker::new("test", "http://localhost/".parse().unwrap(), &toml::from_str::<CircuitBreaker>(config).unwrap())
+    }
+
+    #[test]
+    fn rejected_calls_come_back_spread_over_the_open_period() {
+        let breaker = from_config("failure_threshold = 1\nopen_ms = 60000");
+        let before = SystemTime::now();
+        breaker.admit().unwrap().record(true);
+        assert!(breaker.is_open());
+        let retry_ats: Vec<_> = (0..20).map(|_| breaker.admit().err().unwrap()).collect();
+        for retry_at in &retry_ats {
+            // No earlier than the breaker half-opens, and no later than one more open period after
+            assert!(*retry_at >= before + Duration::from_secs(60));
+            assert!(*retry_at <= SystemTime::now() + Duration::from_secs(120));
+        }
+        assert!(retry_ats.iter().any(|retry_at| *retry_at != retry_ats[0]));
+    }
+
+    #[test]
+    fn calls_beyond_the_half_open_trials_are_put_off_within_the_open_period() {
+        let breaker = from_config("failure_threshold = 1\nopen_ms = 0\nhalf_open_calls = 1");
+        breaker.admit().unwrap().record(true);
+        // Half-opens straight away, and lets one trial through
+        let trial = breaker.admit().unwrap();
+        let retry_at = breaker.admit().err().unwrap();
+        assert!(retry_at <= SystemTime::now());
+        trial.record(false);
+        assert!(breaker.admit().is_ok());
+    }
+
+    #[test]
+    fn dropped_trial_gives_its_place_back() {
+        let breaker = from_config("failure_threshold = 1\nopen_ms = 0\nhalf_open_calls = 1");
+        breaker.admit().unwrap().record(true);
+        drop(breaker.admit().unwrap());
+        assert_eq!(breaker.status().state, "half-open");
+        breaker.admit().unwrap().record(false);
+        assert_eq!(breaker.status().state, "closed");
+    }
+}
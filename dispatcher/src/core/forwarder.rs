@@ -1,5 +1,7 @@
 use anyhow::Result;
 use reqwest::Client;
+use tokio::sync::SemaphorePermit;
+use crate::config::TaskHandler;
 use crate::data::DynamicTaskMessage;
 use crate::core::handler_repo::HandlerRepo;
 
@@ -10,23 +12,54 @@ pub struct Forwarder {
     handlers: HandlerRepo,
 }
 
+/// A task's turn with its handler, which holds its place among the handler's tasks in flight
+pub struct Turn<'a> {
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
 impl Forwarder {
     pub fn new(client: Client, handlers: HandlerRepo) -> Forwarder {
         Forwarder { client, handlers }
     }
 
-    pub async fn process(&self, msg: &DynamicTaskMessage) -> Result<Option<HandleResult>> {
+    /// The config of the handler that would process `msg`, if any
+    pub fn handler_config(&self, msg: &DynamicTaskMessage) -> Option<&TaskHandler> {
+        self.handlers.match_handler(msg).map(|matched| matched.config)
+    }
+
+    /// Waits until the handler that would process `msg` can take another task, which is right
+    /// away unless the handler sets `max_in_flight` or `rate_limit`
+    pub async fn wait_turn(&self, msg: &DynamicTaskMessage) -> Result<Turn<'_>> {
+        let Some(matched) = self.handlers.match_handler(msg) else {
+            return Ok(Turn { _permit: None });
+        };
+        let permit = match matched.in_flight {
+            Some(in_flight) => Some(in_flight.acquire().await?),
+            None => None,
+        };
+        // Waiting for a token while holding the permit keeps the handler's in-flight count
+        if let Some(rate_limiter) = matched.rate_limiter {
+            rate_limiter.acquire().await?;
+        }
+        Ok(Turn { _permit: permit })
+    }
+
+    /// Processes `msg`, which should have its turn with the handler
+    pub async fn process(&self, message_id: &str, msg: &DynamicTaskMessage) -> Result<Option<HandleResult>> {
         // Map TypedMessage to some task schema that we recognize
     
         // validate the message against schema. this should maybe happen in the caller.
     
         // Map msg to the worker we should use
-        let endpoint = self.handlers.match_handler(&msg);
+        let endpoint = self.handlers.match_handler(msg);
 
         // Make the HTTP call    
         let result = match endpoint {
-            Some(handler) => {
-                let result = handler.handle(&self.client, &msg).await?;
+            Some(matched) => {
+                let result = match matched.batcher {
+                    Some(batcher) => batcher.handle(message_id, msg).await?,
+                    None => matched.handler.handle(&self.client, message_id, msg).await?,
+                };
                 Some(result)
             },
             None => {
@@ -1,7 +1,10 @@
+use std::time::{Duration, SystemTime};
+
 use anyhow::Result;
-use reqwest::{Client, StatusCode};
+use reqwest::{Client, Response, StatusCode};
 use async_trait::async_trait;
 use serde::Deserialize;
+use serde_json::Value;
 
 use crate::data::DynamicTaskMessage;
 
@@ -9,8 +12,61 @@ pub enum HandleResult {
     /// Successful processing that should continue with the tasks in the parsed WorkerResponse
     Continue { status: StatusCode, response: WorkerResponse },
 
+    /// Successful processing that should continue with the tasks the worker is streaming
+    ContinueStream { status: StatusCode, tasks: TaskStream },
+
     /// Successful processing with un unparseable response
     ContinueUnparseable { status: StatusCode, text: String },
+
+    /// Failed processing that should be attempted again, no sooner than `retry_after` if the
+    /// worker gave a time
+    Retry { status: StatusCode, retry_after: Option<Duration>, reason: String },
+
+    /// Failed processing that shouldn't be attempted again
+    Fail { status: StatusCode, reason: String },
+
+    /// The task should be handled again at the given time, without counting as an attempt
+    Defer { status: StatusCode, until: SystemTime },
+
+    /// The worker accepted the task, and will respond through its callback
+    Accepted { status: StatusCode },
+
+    /// The worker wasn't called because its circuit breaker is open, and the task should be
+    /// handled again at the given time, without counting as an attempt
+    CircuitOpen { until: SystemTime },
+}
+
+impl HandleResult {
+    /// Whether the worker responded with 5xx, which suggests it's unhealthy
+    pub fn is_server_error(&self) -> bool {
+        matches!(self, HandleResult::Retry { status, .. } if status.is_server_error())
+    }
+
+    /// Interprets the body of a successful response, or of a worker's callback. A body with
+    /// a `result` decides the result itself.
+    pub fn from_body(status: StatusCode, retry_after: Option<Duration>, text: &str) -> HandleResult {
+        if let Ok(directive) = serde_json::from_str::<WorkerDirective>(text) {
+            return directive.into_result(status, retry_after);
+        }
+        match serde_json::from_str::<WorkerResponse>(text) {
+            Ok(worker_response) => HandleResult::Continue { status, response: worker_response },
+            Err(_) =>
+                // Handlers with `strict` set send these to the dead-letter topic instead of continuing
+                HandleResult::ContinueUnparseable { status, text: text.to_owned() }
+        }
+    }
+}
+
+/// Longest part of a worker's output kept as the reason for a failure
+const MAX_REASON_CHARS: usize = 200;
+
+/// The start of a worker's output explaining a failure, or None if there's no output
+pub fn excerpt(text: &str) -> Option<String> {
+    match text.trim() {
+        "" => None,
+        text if text.chars().count() > MAX_REASON_CHARS => Some(format!("{}...", text.chars().take(MAX_REASON_CHARS).collect::<String>())),
+        text => Some(text.to_owned()),
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -19,7 +75,102 @@ pub struct WorkerResponse {
     pub tasks: Vec<DynamicTaskMessage>,
 }
 
+/// The tasks in a response streamed as newline-delimited JSON, one task per line. Lines are
+/// read as they're asked for, so only the part of the response not yet asked for is buffered,
+/// and the worker is held back while the tasks it has sent are published.
+pub struct TaskStream {
+    response: Response,
+    buffer: Vec<u8>,
+}
+
+impl TaskStream {
+    /// Media type of a streamed response
+    pub const CONTENT_TYPE: &'static str = "application/x-ndjson";
+
+    pub fn new(response: Response) -> TaskStream {
+        TaskStream { response, buffer: Vec::new() }
+    }
+
+    /// The next task, or None at the end of the response. Blank lines are skipped.
+    pub async fn next(&mut self) -> Result<Option<DynamicTaskMessage>> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                if line.trim_ascii().is_empty() {
+                    continue;
+                }
+                return Ok(Some(serde_json::from_slice(&line)?));
+            }
+            match self.response.chunk().await? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                // The last line needn't end with a newline
+                None if self.buffer.trim_ascii().is_empty() => return Ok(None),
+                None => return Ok(Some(serde_json::from_slice(&std::mem::take(&mut self.buffer))?)),
+            }
+        }
+    }
+}
+
+/// A response in which the worker decides the result itself, whatever the response's status
+#[derive(Deserialize, Debug)]
+pub struct WorkerDirective {
+    pub result: Directive,
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// For `retry`, the least time to wait before the next attempt, and for `defer` how long
+    /// to wait, e.g. `"30s"`
+    #[serde(default, with = "humantime_serde")]
+    pub delay: Option<Duration>,
+    /// For `defer`, when to handle the task again, given as an RFC 3339 timestamp
+    #[serde(default, with = "crate::data::rfc3339::option")]
+    pub deliver_at: Option<SystemTime>,
+}
+
+impl WorkerDirective {
+    fn into_result(self, status: StatusCode, retry_after: Option<Duration>) -> HandleResult {
+        let reason = self.reason.unwrap_or_else(|| "no reason given".to_owned());
+        match self.result {
+            Directive::Fail => HandleResult::Fail { status, reason },
+            Directive::Retry => HandleResult::Retry { status, retry_after: self.delay.or(retry_after), reason },
+            Directive::Defer => {
+                let delayed = self.delay.map(|delay| SystemTime::now() + delay);
+                match (self.deliver_at, delayed) {
+                    (Some(a), Some(b)) => HandleResult::Defer { status, until: a.max(b) },
+                    (Some(until), None) | (None, Some(until)) => HandleResult::Defer { status, until },
+                    (None, None) => HandleResult::Fail { status, reason: "worker deferred the task without a `delay` or `deliver_at`".to_owned() },
+                }
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Directive {
+    Fail,
+    Retry,
+    Defer,
+}
+
 #[async_trait]
-pub trait Handler {
-    async fn handle(&self, client: &Client, task: &DynamicTaskMessage) -> Result<HandleResult>;
+pub trait Handler: Send + Sync {
+    /// Handles `task`, which arrived in the message `message_id`
+    async fn handle(&self, client: &Client, message_id: &str, task: &DynamicTaskMessage) -> Result<HandleResult>;
+
+    /// Handles several tasks, each along with the ID of the message it arrived in, returning
+    /// a result for each task in the same order. By default they're handled one at a time.
+    async fn handle_batch(&self, client: &Client, tasks: &[(&str, &DynamicTaskMessage)]) -> Result<Vec<HandleResult>> {
+        let mut results = Vec::with_capacity(tasks.len());
+        for (message_id, task) in tasks {
+            results.push(self.handle(client, message_id, task).await?);
+        }
+        Ok(results)
+    }
+}
+
+/// The response to a batch of tasks, with a result for each task in the order they were sent
+#[derive(Deserialize, Debug)]
+pub struct BatchResponse {
+    /// Each is interpreted like the body of a response to a single task
+    pub results: Vec<Value>,
 }
\ No newline at end of file
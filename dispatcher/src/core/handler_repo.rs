@@ -1,79 +1,200 @@
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::Arc;
 
-use reqwest::Url;
+use reqwest::{Client, Url};
 use rune::Source;
+use tokio::sync::Semaphore;
 
 use crate::data::DynamicTaskMessage;
 use crate::config::TaskHandler;
+use crate::mq::MessageQueue;
 
+use super::balancer::Balancer;
+use super::breaker::Breaker;
+use super::batch::Batcher;
 use super::handler::Handler;
+use super::rate_limit::RateLimiter;
 use super::rune::RuneScript;
+use super::subprocess::Subprocess;
 use super::worker::Worker;
 
 #[derive(PartialEq, Eq, Hash, Clone)]
 enum HandlerDef {
-    Endpoint(Url),
+    /// An endpoint, along with the index of its config entry, since each entry has its own
+    /// HTTP settings for calling it
+    Endpoint(usize, Url),
+    /// Several endpoints that tasks are spread across
+    Endpoints(usize, Vec<Url>),
     Pipeline(String),
+    /// A command, along with the index of its config entry, which has its settings for running it
+    Command(usize),
+}
+
+type Matcher = Box<dyn Fn(&DynamicTaskMessage) -> Option<HandlerDef> + Send + Sync>;
+
+/// A config entry along with the state needed to route tasks to its handler
+struct Route {
+    config: TaskHandler,
+    matcher: Matcher,
+    /// Bounds the entry's in-flight tasks, if it sets `max_in_flight`
+    in_flight: Option<Semaphore>,
+    /// Collects the entry's tasks into batches, if it sets `batch`
+    batcher: Option<Batcher>,
+    /// Limits how fast the entry's tasks are sent, if it sets `rate_limit`
+    rate_limiter: Option<RateLimiter>,
 }
 
 pub struct HandlerRepo {
-    handlers: HashMap<HandlerDef, Box<dyn Handler>>,
-    matchers: Vec<Box<dyn Fn(&DynamicTaskMessage) -> Option<HandlerDef>>>,
+    handlers: HashMap<HandlerDef, Arc<dyn Handler>>,
+    routes: Vec<Route>,
+    breakers: Vec<Arc<Breaker>>,
+}
+
+/// A handler matched for a task, along with the config entry that selected it
+pub struct MatchedHandler<'a> {
+    pub config: &'a TaskHandler,
+    pub handler: &'a dyn Handler,
+    pub in_flight: Option<&'a Semaphore>,
+    pub batcher: Option<&'a Batcher>,
+    pub rate_limiter: Option<&'a RateLimiter>,
 }
 
 impl HandlerRepo {
-    pub fn new(config: &[TaskHandler]) -> Result<HandlerRepo, anyhow::Error> {
-        // config.to_vec() is needed because Box<dyn Trait> is implicitly + 'static
-        // and config.to_vec() does a deep clone that avoids requiring
-        // &'static on config although that could also be fine
-        let handler_defs = config.to_vec().into_iter()
-            .map(|c| 
-                match (&c.endpoint, &c.pipeline) {
-                    (Some(endpoint), _) => {
+    /// Asynchronous handlers' workers are given callback URLs starting with `callback_url`.
+    /// Batches and health checks are sent with `client`, and shared rate limits are kept in `mq`.
+    pub fn new(config: &[TaskHandler], callback_url: Option<&str>, client: &Client, mq: Arc<dyn MessageQueue>) -> Result<HandlerRepo, anyhow::Error> {
+        let callback_url = callback_url.map(Url::from_str).transpose()?;
+        if callback_url.is_none() && config.iter().any(|c| c.callback.is_some()) {
+            return Err(anyhow::Error::msg("config `dispatch.callback_url` is required for handlers with callbacks"));
+        }
+        for c in config {
+            match &c.batch {
+                Some(_) if c.callback.is_some() =>
+                    return Err(anyhow::Error::msg(format!("handler for {} can't have both `batch` and `callback`", c.task_selector.type_name))),
+                Some(batch) if batch.max_size == 0 =>
+                    return Err(anyhow::Error::msg(format!("handler for {} has a `batch.max_size` of 0", c.task_selector.type_name))),
+                _ => {},
+            }
+            if c.command.is_some() && c.callback.is_some() {
+                return Err(anyhow::Error::msg(format!("handler for {} can't have both `command` and `callback`", c.task_selector.type_name)));
+            }
+            if c.rate_limit.as_ref().is_some_and(|rate_limit| !rate_limit.per_second.is_finite() || rate_limit.per_second <= 0.0) {
+                return Err(anyhow::Error::msg(format!("handler for {} needs a positive `rate_limit.per_second`", c.task_selector.type_name)));
+            }
+            if !mq.shares_values() && (c.callback.is_some() || c.rate_limit.as_ref().is_some_and(|rate_limit| rate_limit.shared)) {
+                log::warn!("handler for {} has a `callback` or shared `rate_limit`, whose values this backend keeps in the Dispatcher, so only one Dispatcher may run it", c.task_selector.type_name);
+            }
+            if c.max_in_flight == Some(0) {
+                return Err(anyhow::Error::msg(format!("handler for {} has a `max_in_flight` of 0", c.task_selector.type_name)));
+            }
+            if c.rate_limit.as_ref().is_some_and(|rate_limit| rate_limit.burst == Some(0)) {
+                return Err(anyhow::Error::msg(format!("handler for {} has a `rate_limit.burst` of 0", c.task_selector.type_name)));
+            }
+        }
+        // config.iter().cloned() is needed because Arc<dyn Trait> is implicitly + 'static
+        // and cloning avoids requiring &'static on config although that could also be fine
+        let handler_defs = config.iter().cloned().enumerate()
+            .map(|(index, c)| 
+                match (&c.endpoint, c.endpoints.as_slice(), &c.pipeline, &c.command) {
+                    (Some(_), [_, ..], _, _) => {
+                        Err(anyhow::Error::msg(format!("handler for {} sets both `endpoint` and `endpoints`", c.task_selector.type_name)))
+                    },
+                    (Some(endpoint), _, _, _) => {
                         // We parse proper Url's here, early
                         // so that startup fails if any of them fail to parse
-                        let url = Url::from_str(&endpoint)?;
-                        Ok((c, HandlerDef::Endpoint(url)))
+                        let url = Url::from_str(endpoint)?;
+                        Ok((c, HandlerDef::Endpoint(index, url)))
                     },
-                    (_, Some(pipeline)) => {
+                    (None, [_, ..], _, _) => {
+                        let urls = c.endpoints.iter().map(|e| Url::from_str(&e.url)).collect::<Result<Vec<_>, _>>()?;
+                        Ok((c, HandlerDef::Endpoints(index, urls)))
+                    },
+                    (None, [], Some(pipeline), _) => {
                         Ok((c.clone(), HandlerDef::Pipeline(pipeline.clone())))
                     }
-                    (None, None) => {
-                        Err(anyhow::Error::msg("no endpoint or pipeline specified"))
+                    (None, [], None, Some(_)) => {
+                        Ok((c, HandlerDef::Command(index)))
+                    }
+                    (None, [], None, None) => {
+                        Err(anyhow::Error::msg("no endpoint, pipeline or command specified"))
                     }
                 } 
             )
             .collect::<Result<Vec<_>, _>>()?;
-        let handlers = handler_defs.iter().map(|(_, def)| {
+        let mut breakers = Vec::new();
+        // Each endpoint has a circuit breaker of its own
+        let mut new_worker = |c: &TaskHandler, url: &Url| -> Result<Worker, anyhow::Error> {
+            let worker = Worker::new(url.clone(), &c.http, c.retry.as_ref(), callback_url.as_ref())?;
+            Ok(match &c.circuit_breaker {
+                Some(config) => {
+                    let breaker = Arc::new(Breaker::new(&c.task_selector.type_name, worker.redacted_endpoint(), config));
+                    breakers.push(breaker.clone());
+                    worker.with_breaker(breaker)
+                },
+                None => worker,
+            })
+        };
+        let handlers = handler_defs.iter().map(|(c, def)| {
             match def {
-                HandlerDef::Endpoint(url) => 
-                    Ok((def.clone(), Box::new(Worker { endpoint: url.clone() }) as Box<dyn Handler>)),
+                HandlerDef::Endpoint(_, url) => 
+                    Ok((def.clone(), Arc::new(new_worker(c, url)?) as Arc<dyn Handler>)),
+                HandlerDef::Endpoints(_, urls) => {
+                    let workers = urls.iter().zip(&c.endpoints)
+                        .map(|(url, e)| Ok((new_worker(c, url)?, e.weight)))
+                        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+                    Ok((def.clone(), Arc::new(Balancer::new(workers, &c.balancing, client)) as Arc<dyn Handler>))
+                },
+                HandlerDef::Command(_) => {
+                    let command = c.command.as_deref().unwrap_or_default();
+                    Ok((def.clone(), Arc::new(Subprocess::new(command, &c.exec)?) as Arc<dyn Handler>))
+                },
                 HandlerDef::Pipeline(pipeline) => {
                     // TODO: this should actually load and validate the pipeline
                     let source = Source::from_path(pipeline)?;
-                    Ok((def.clone(), Box::new(RuneScript::new(source)?) as Box<dyn Handler>))
+                    Ok((def.clone(), Arc::new(RuneScript::new(source)?) as Arc<dyn Handler>))
                 }
             }
-        }).collect::<Result<HashMap<HandlerDef, Box<dyn Handler>>, anyhow::Error>>()?;
-        let matchers = handler_defs
+        }).collect::<Result<HashMap<HandlerDef, Arc<dyn Handler>>, anyhow::Error>>()?;
+        let routes: Vec<Route> = handler_defs
             .into_iter()
             .map(|(c, handler_key)| { 
-                Box::new(move |msg: &DynamicTaskMessage| -> Option<HandlerDef> {
-                    if msg.type_name == c.task_selector.type_name {
+                let type_name = c.task_selector.type_name.clone();
+                let batcher = c.batch.as_ref()
+                    .map(|batch| Batcher::start(handlers[&handler_key].clone(), client.clone(), batch));
+                let matcher = Box::new(move |msg: &DynamicTaskMessage| -> Option<HandlerDef> {
+                    if msg.type_name == type_name {
                         Some(handler_key.clone())
                     } else {
                         None
                     }
-                }) as Box<_>
-            });
-        Ok(HandlerRepo { handlers, matchers: Vec::from_iter(matchers) })
+                }) as Matcher;
+                let in_flight = c.max_in_flight.map(Semaphore::new);
+                let rate_limiter = c.rate_limit.as_ref()
+                    .map(|rate_limit| RateLimiter::new(&c.task_selector.type_name, rate_limit, mq.clone()));
+                Route { config: c, matcher, in_flight, batcher, rate_limiter }
+            })
+            .collect();
+        Ok(HandlerRepo { handlers, routes, breakers })
+    }
+
+    /// The circuit breakers of every endpoint that has one
+    pub fn breakers(&self) -> Vec<Arc<Breaker>> {
+        self.breakers.clone()
     }
 
-    pub fn match_handler(&self, msg: &DynamicTaskMessage) -> Option<&Box<dyn Handler>> {
-        self.matchers.iter()
-            .find_map(|f| f(msg))
-            .and_then(|key| self.handlers.get(&key))
+    pub fn match_handler(&self, msg: &DynamicTaskMessage) -> Option<MatchedHandler<'_>> {
+        self.routes.iter()
+            .find_map(|route| (route.matcher)(msg).map(|key| (route, key)))
+            .and_then(|(route, key)| {
+                self.handlers.get(&key).map(|handler| MatchedHandler {
+                    config: &route.config,
+                    handler: handler.as_ref(),
+                    in_flight: route.in_flight.as_ref(),
+                    batcher: route.batcher.as_ref(),
+                    rate_limiter: route.rate_limiter.as_ref(),
+                })
+            })
     }
 }
 
@@ -1,10 +1,19 @@
+mod balancer;
+mod batch;
+mod breaker;
 mod forwarder;
 mod handler;
 mod worker;
 mod handler_repo;
+mod rate_limit;
+mod retry;
+mod signing;
+mod subprocess;
 
 mod rune;
 
 pub use handler_repo::HandlerRepo;
+pub use breaker::Breaker;
 pub use handler::{HandleResult, WorkerResponse};
-pub use forwarder::Forwarder;
\ No newline at end of file
+pub use forwarder::Forwarder;
+pub use retry::RetryPolicy;
\ No newline at end of file
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::RateLimit;
+use crate::mq::MessageQueue;
+
+/// A token bucket's contents, which is also the value of a shared bucket
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    /// When `tokens` was last brought up to date, in milliseconds since the Unix epoch, so that
+    /// Dispatchers sharing the bucket agree on it
+    updated_at_ms: u64,
+}
+
+impl Bucket {
+    fn now_ms() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+    }
+
+    /// Adds the tokens accrued since the bucket was last brought up to date
+    fn refill(self, per_second: f64, burst: f64) -> Bucket {
+        let now_ms = Self::now_ms();
+        let elapsed = now_ms.saturating_sub(self.updated_at_ms) as f64 / 1000.0;
+        Bucket { tokens: (self.tokens + elapsed * per_second).min(burst), updated_at_ms: now_ms.max(self.updated_at_ms) }
+    }
+}
+
+/// Limits how fast a handler's tasks are sent. Tasks over the limit wait their turn, in the
+/// order they arrived at this Dispatcher.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    /// Only the task at the front of the line takes a token, so it holds the lock while it waits
+    local: Mutex<Bucket>,
+    /// The queue and key of a shared bucket, used instead of `local`
+    shared: Option<(Arc<dyn MessageQueue>, String)>,
+}
+
+impl RateLimiter {
+    /// A shared bucket is kept under a key named for the handler's task type
+    pub fn new(type_name: &str, config: &RateLimit, mq: Arc<dyn MessageQueue>) -> RateLimiter {
+        let burst = config.burst.map_or(config.per_second.max(1.0), f64::from);
+        RateLimiter {
+            per_second: config.per_second,
+            burst,
+            local: Mutex::new(Bucket { tokens: burst, updated_at_ms: Bucket::now_ms() }),
+            shared: config.shared.then(|| (mq, format!("rate-limit.{}", type_name))),
+        }
+    }
+
+    /// Waits until a token is available and takes it
+    pub async fn acquire(&self) -> Result<()> {
+        let mut local = self.local.lock().await;
+        loop {
+            let wait = match &self.shared {
+                Some((mq, key)) => self.take_shared(mq.as_ref(), key).await?,
+                None => {
+                    *local = local.refill(self.per_second, self.burst);
+                    self.take(&mut local)
+                },
+            };
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Takes a token from the bucket, or returns how long until there is one
+    fn take(&self, bucket: &mut Bucket) -> Option<Duration> {
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return None;
+        }
+        Some(Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second))
+    }
+
+    async fn take_shared(&self, mq: &dyn MessageQueue, key: &str) -> Result<Option<Duration>> {
+        loop {
+            let current = mq.get_value(key).await?;
+            let mut bucket = match &current {
+                Some(value) => serde_json::from_str::<Bucket>(value)?.refill(self.per_second, self.burst),
+                None => Bucket { tokens: self.burst, updated_at_ms: Bucket::now_ms() },
+            };
+            if let Some(wait) = self.take(&mut bucket) {
+                return Ok(Some(wait));
+            }
+            // Another Dispatcher may have taken a token in between, in which case this tries again
+            if mq.compare_and_set(key, current.as_deref(), &serde_json::to_string(&bucket)?).await? {
+                return Ok(None);
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::config::Retry;
+
+/// Decides whether and when a failed task is attempted again
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &Retry) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: config.max_attempts,
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+            jitter: config.jitter.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Whether another attempt may follow the given (1-based) attempt
+    pub fn should_retry(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+
+    /// Exponential backoff to wait after the given (1-based) attempt fails, with jitter
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let backoff = self.initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        if self.jitter == 0.0 {
+            return backoff;
+        }
+        // Spread retries over [backoff * (1 - jitter), backoff]
+        let factor = 1.0 - self.jitter * rand::thread_rng().gen::<f64>();
+        backoff.mul_f64(factor)
+    }
+
+    /// How long to wait after the given attempt fails: the backoff, or longer if the worker
+    /// asked to wait `retry_after`
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        self.backoff(attempt).max(retry_after.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::Retry;
+
+    use super::RetryPolicy;
+
+    fn from_config(config: &str) -> RetryPolicy {
+        RetryPolicy::new(&toml::from_str::<Retry>(config).unwrap())
+    }
+
+    #[test]
+    fn retries_until_max_attempts() {
+        let policy = from_config("max_attempts = 3");
+        assert!(policy.should_retry(1));
+        assert!(policy.should_retry(2));
+        assert!(!policy.should_retry(3));
+        assert!(!policy.should_retry(4));
+        assert!(!from_config("max_attempts = 1").should_retry(1));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_backoff() {
+        let policy = from_config("initial_backoff_ms = 100\nmax_backoff_ms = 1000\njitter = 0.0");
+        let backoffs: Vec<_> = (1..=6).map(|attempt| policy.backoff(attempt).as_millis()).collect();
+        assert_eq!(backoffs, [100, 200, 400, 800, 1000, 1000]);
+        // Doesn't overflow, however many attempts there have been
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn jitter_shortens_backoff_by_at_most_its_fraction() {
+        let policy = from_config("initial_backoff_ms = 1000\njitter = 0.25");
+        for _ in 0..1000 {
+            let backoff = policy.backoff(1);
+            assert!(backoff >= Duration::from_millis(750) && backoff <= Duration::from_millis(1000), "backoff {:?}", backoff);
+        }
+        // Out of range jitter is clamped
+        let backoff = from_config("initial_backoff_ms = 1000\njitter = 2.0").backoff(1);
+        assert!(backoff <= Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn retry_after_is_waited_if_longer_than_the_backoff() {
+        let policy = from_config("initial_backoff_ms = 1000\njitter = 0.0");
+        assert_eq!(policy.delay(1, None), Duration::from_secs(1));
+        assert_eq!(policy.delay(1, Some(Duration::from_secs(30))), Duration::from_secs(30));
+        assert_eq!(policy.delay(1, Some(Duration::from_millis(10))), Duration::from_secs(1));
+    }
+}
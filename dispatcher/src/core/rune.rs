@@ -107,24 +107,21 @@ impl RuneScript {
         let r = rune::from_value::<Result<Vec<TrampolineTask>>>(r.into_result()?)
             .context("error unmarshalling script Result")?
             .context("script returned Result::Err")?;
-        let r2 = r.into_iter().map(|t| Self::to_message(t)).collect::<Result<Vec<_>>>()?;
+        let r2 = r.into_iter().map(Self::to_message).collect::<Result<Vec<_>>>()?;
         tx.send(r2).map_err(|e| anyhow!("failed to send script result, maybe receiver is dropped? {:?}", e))?;
         Ok(())
     }
 
     fn to_message(t: TrampolineTask) -> Result<DynamicTaskMessage> {
         let json = serde_json::to_value(t.task)?;
-        Ok(DynamicTaskMessage {
-            type_name: t.type_name,
-            task: json
-        })
+        Ok(DynamicTaskMessage::new(t.type_name, json))
     }
 
 }
 
 #[async_trait]
 impl Handler for RuneScript {
-    async fn handle(&self, client: &Client, task: &DynamicTaskMessage) -> Result<HandleResult> {
+    async fn handle(&self, client: &Client, _message_id: &str, task: &DynamicTaskMessage) -> Result<HandleResult> {
         let (tx, rx) = tokio::sync::oneshot::channel::<Vec<DynamicTaskMessage>>();
         self.execute(tx, client, task).await?;
         let result = rx.await?;
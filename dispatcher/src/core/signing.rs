@@ -0,0 +1,131 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::Signing;
+
+/// Header with the hex-encoded HMAC-SHA256 of `{timestamp}.{body}`
+pub const SIGNATURE_HEADER: &str = "Trampoline-Signature";
+/// Header with when the request was signed, in seconds since the epoch
+pub const TIMESTAMP_HEADER: &str = "Trampoline-Timestamp";
+/// Header with the ID of the key the request was signed with
+pub const KEY_ID_HEADER: &str = "Trampoline-Key-Id";
+
+struct Key {
+    id: String,
+    secret: Vec<u8>,
+    active_from: SystemTime,
+}
+
+/// Signs request bodies with the configured key set. The timestamp is signed along with the
+/// body so that workers can reject replayed requests.
+pub struct Signer {
+    /// Ordered by when each key becomes active
+    keys: Vec<Key>,
+}
+
+impl Signer {
+    pub fn new(config: &Signing) -> Result<Signer> {
+        if config.keys.is_empty() {
+            bail!("request signing needs at least one key");
+        }
+        let mut keys = config.keys.iter()
+            .map(|key| Ok(Key {
+                id: key.id.clone(),
+                secret: key.secret.read().context(format!("reading signing key `{}` failed", key.id))?.into_bytes(),
+                active_from: key.active_from.unwrap_or(UNIX_EPOCH),
+            }))
+            .collect::<Result<Vec<_>>>()?;
+        keys.sort_by_key(|key| key.active_from);
+        Ok(Signer { keys })
+    }
+
+    /// The headers to send with `body`
+    pub fn sign(&self, body: &[u8]) -> Result<[(&'static str, String); 3]> {
+        let now = SystemTime::now();
+        let key = self.keys.iter().rev().find(|key| key.active_from <= now)
+            .context("no signing key is active yet")?;
+        let timestamp = now.duration_since(UNIX_EPOCH)?.as_secs().to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key.secret)?;
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        let signature = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+        Ok([
+            (SIGNATURE_HEADER, signature),
+            (TIMESTAMP_HEADER, timestamp),
+            (KEY_ID_HEADER, key.id.clone()),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use crate::config::Signing;
+
+    use super::{Signer, KEY_ID_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+
+    /// A signer with the key set `keys`, in TOML
+    fn from_config(keys: &str) -> anyhow::Result<Signer> {
+        Signer::new(&toml::from_str::<Signing>(keys).unwrap())
+    }
+
+    fn header<'a>(headers: &'a [(&'static str, String); 3], name: &str) -> &'a str {
+        &headers.iter().find(|(header, _)| *header == name).unwrap().1
+    }
+
+    /// Whether the headers carry a valid signature of `body` with `secret`
+    fn verifies(headers: &[(&'static str, String); 3], secret: &str, body: &[u8]) -> bool {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(header(headers, TIMESTAMP_HEADER).as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        let expected: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+        header(headers, SIGNATURE_HEADER) == expected
+    }
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        std::env::set_var("SIGNING_TEST_SECRET", "s3cret");
+        let headers = from_config(r#"keys = [{ id = "k1", secret = { env = "SIGNING_TEST_SECRET" } }]"#).unwrap()
+            .sign(b"{\"to\": \"a@example.com\"}").unwrap();
+        assert_eq!(header(&headers, KEY_ID_HEADER), "k1");
+        let timestamp: u64 = header(&headers, TIMESTAMP_HEADER).parse().unwrap();
+        let signed_at = UNIX_EPOCH + Duration::from_secs(timestamp);
+        assert!(SystemTime::now().duration_since(signed_at).unwrap() < Duration::from_secs(5));
+        assert!(verifies(&headers, "s3cret", b"{\"to\": \"a@example.com\"}"));
+        assert!(!verifies(&headers, "s3cret", b"{\"to\": \"b@example.com\"}"));
+        assert!(!verifies(&headers, "other", b"{\"to\": \"a@example.com\"}"));
+    }
+
+    #[test]
+    fn signs_with_the_latest_active_key() {
+        std::env::set_var("SIGNING_TEST_OLD", "old");
+        std::env::set_var("SIGNING_TEST_NEW", "new");
+        std::env::set_var("SIGNING_TEST_NEXT", "next");
+        let signer = from_config(r#"keys = [
+            { id = "next", secret = { env = "SIGNING_TEST_NEXT" }, active_from = "2999-01-01T00:00:00Z" },
+            { id = "new", secret = { env = "SIGNING_TEST_NEW" }, active_from = "2020-01-01T00:00:00Z" },
+            { id = "old", secret = { env = "SIGNING_TEST_OLD" } },
+        ]"#).unwrap();
+        let headers = signer.sign(b"{}").unwrap();
+        assert_eq!(header(&headers, KEY_ID_HEADER), "new");
+        assert!(verifies(&headers, "new", b"{}"));
+    }
+
+    #[test]
+    fn needs_an_active_key() {
+        std::env::set_var("SIGNING_TEST_LATER", "later");
+        let signer = from_config(r#"keys = [{ id = "later", secret = { env = "SIGNING_TEST_LATER" }, active_from = "2999-01-01T00:00:00Z" }]"#).unwrap();
+        assert!(signer.sign(b"{}").is_err());
+        assert!(from_config("keys = []").is_err());
+        assert!(from_config(r#"keys = [{ id = "unset", secret = { env = "SIGNING_TEST_UNSET" } }]"#).is_err());
+    }
+}
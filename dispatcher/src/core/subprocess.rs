@@ -0,0 +1,264 @@
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Semaphore;
+
+use crate::config::Exec;
+use crate::data::DynamicTaskMessage;
+
+use super::handler::{excerpt, HandleResult, Handler, WorkerDirective};
+
+/// Environment variables telling a command about the task on its stdin, like the headers sent
+/// to an endpoint
+pub const TASK_TYPE_ENV: &str = "TRAMPOLINE_TASK_TYPE";
+pub const TASK_ID_ENV: &str = "TRAMPOLINE_TASK_ID";
+pub const MESSAGE_ID_ENV: &str = "TRAMPOLINE_MESSAGE_ID";
+pub const ATTEMPT_ENV: &str = "TRAMPOLINE_ATTEMPT";
+pub const RUN_ID_ENV: &str = "TRAMPOLINE_RUN_ID";
+pub const PARENT_ID_ENV: &str = "TRAMPOLINE_PARENT_ID";
+pub const TIMEOUT_ENV: &str = "TRAMPOLINE_TIMEOUT_MS";
+
+/// A pooled process, kept running between tasks
+struct Process {
+    // Killed when dropped
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+/// Processes kept running for a handler with `pool_size` set
+struct Pool {
+    /// One permit for each process, held while it handles a task
+    slots: Semaphore,
+    idle: Mutex<Vec<Process>>,
+}
+
+/// Handles tasks by running a local command. By default a process is started for each task,
+/// which reads the task from stdin, and writes its response to stdout, with its exit code
+/// deciding the result. A pooled process instead reads one task envelope per line, and writes
+/// one response per line.
+pub struct Subprocess {
+    program: String,
+    args: Vec<String>,
+    exec: Exec,
+    pool: Option<Pool>,
+}
+
+impl Subprocess {
+    pub fn new(command: &[String], exec: &Exec) -> Result<Subprocess> {
+        let Some((program, args)) = command.split_first() else {
+            bail!("`command` is empty");
+        };
+        let pool = match exec.pool_size {
+            Some(0) => bail!("`exec.pool_size` must be at least 1"),
+            Some(size) => Some(Pool { slots: Semaphore::new(size), idle: Mutex::new(Vec::new()) }),
+            None => None,
+        };
+        Ok(Subprocess { program: program.clone(), args: args.to_vec(), exec: exec.clone(), pool })
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args)
+            .envs(&self.exec.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(working_dir) = &self.exec.working_dir {
+            command.current_dir(working_dir);
+        }
+        command
+    }
+
+    /// The configured timeout, shortened to the task's remaining time
+    fn timeout(&self, task: &DynamicTaskMessage) -> Result<Option<Duration>> {
+        let timeout = self.exec.timeout_ms.map(Duration::from_millis);
+        match task.remaining() {
+            Some(Duration::ZERO) => bail!("deadline passed before the command was run"),
+            Some(remaining) => Ok(Some(timeout.map_or(remaining, |timeout| timeout.min(remaining)))),
+            None => Ok(timeout),
+        }
+    }
+
+    /// Runs a process for the task, which is killed if it runs out of time
+    async fn run(&self, message_id: &str, task: &DynamicTaskMessage) -> Result<HandleResult> {
+        let timeout = self.timeout(task)?;
+        let mut command = self.command();
+        command.stderr(Stdio::piped())
+            .env(TASK_TYPE_ENV, &task.type_name)
+            .env(TASK_ID_ENV, &task.id)
+            .env(MESSAGE_ID_ENV, message_id)
+            .env(ATTEMPT_ENV, task.attempt.to_string())
+            .env(RUN_ID_ENV, task.run_id());
+        if let Some(parent_id) = &task.parent_id {
+            command.env(PARENT_ID_ENV, parent_id);
+        }
+        if let Some(timeout) = timeout {
+            command.env(TIMEOUT_ENV, timeout.as_millis().to_string());
+        }
+        let mut child = command.spawn().context(format!("starting `{}` failed", self.program))?;
+        let mut stdin = child.stdin.take().context("command has no stdin")?;
+        let input = serde_json::to_vec(&task.task)?;
+        // Written while the output is read, so that a process writing before it has read all its
+        // input isn't blocked. A process that doesn't read its input at all is fine too.
+        let write = async move {
+            let _ = stdin.write_all(&input).await;
+        };
+        let run = async { tokio::join!(write, child.wait_with_output()).1 };
+        let output = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, run).await
+                .map_err(|_| anyhow!("`{}` timed out after {:?}", self.program, timeout))??,
+            None => run.await?,
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let result = self.result(output.status.code(), &stdout, &stderr);
+
+        log::info!("ran `{}` for message {} {}, {}, output {}", self.program, &task.type_name, &task.task, output.status, stdout.trim_end());
+        Ok(result)
+    }
+
+    /// Interprets a process's exit code and output. Output with a `result` decides the result
+    /// whatever the exit code, like the body of an endpoint's response. Otherwise 0 is success,
+    /// `retry_exit_codes` and being killed by a signal are retried, and other codes are failures.
+    /// These show as statuses 200, 503 and 500 respectively.
+    fn result(&self, code: Option<i32>, stdout: &str, stderr: &str) -> HandleResult {
+        if code == Some(0) || serde_json::from_str::<WorkerDirective>(stdout).is_ok() {
+            return HandleResult::from_body(StatusCode::OK, None, stdout);
+        }
+        let status = match code {
+            Some(code) => format!("exited with code {}", code),
+            None => "killed by a signal".to_owned(),
+        };
+        let reason = match excerpt(stderr) {
+            Some(stderr) => format!("{}: {}", status, stderr),
+            None => status,
+        };
+        match code {
+            Some(code) if !self.exec.retry_exit_codes.contains(&code) =>
+                HandleResult::Fail { status: StatusCode::INTERNAL_SERVER_ERROR, reason },
+            _ => HandleResult::Retry { status: StatusCode::SERVICE_UNAVAILABLE, retry_after: None, reason },
+        }
+    }
+
+    fn start(&self) -> Result<Process> {
+        // A pooled process's stderr goes to the Dispatcher's, since nothing reads it per task
+        let mut child = self.command().spawn().context(format!("starting `{}` failed", self.program))?;
+        let stdin = child.stdin.take().context("command has no stdin")?;
+        let stdout = BufReader::new(child.stdout.take().context("command has no stdout")?);
+        Ok(Process { child, stdin, stdout })
+    }
+
+    /// Has a pooled process handle the task. A process that exits while handling it gives a
+    /// result from its exit code as in one-shot mode, and one that runs out of time is killed.
+    /// Either way a new process takes its place in the pool.
+    async fn run_pooled(&self, pool: &Pool, task: &DynamicTaskMessage) -> Result<HandleResult> {
+        let timeout = self.timeout(task)?;
+        let _slot = pool.slots.acquire().await?;
+        let idle = pool.idle.lock().unwrap().pop();
+        let mut process = match idle {
+            Some(process) => process,
+            None => self.start()?,
+        };
+        // The whole envelope, since a pooled process's environment can't carry the task's metadata
+        let mut line = serde_json::to_string(task)?;
+        line.push('\n');
+        let exchange = async {
+            let written = async {
+                process.stdin.write_all(line.as_bytes()).await?;
+                process.stdin.flush().await
+            }.await;
+            let mut response = String::new();
+            if written.is_ok() && process.stdout.read_line(&mut response).await? > 0 {
+                return Ok(Ok(response));
+            }
+            // The process exited, before reading the task or before responding to it
+            Ok(Err(process.child.wait().await?))
+        };
+        let exchanged: Result<_> = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, exchange).await
+                .map_err(|_| anyhow!("`{}` timed out after {:?}", self.program, timeout))
+                .and_then(|exchanged| exchanged),
+            None => exchange.await,
+        };
+        match exchanged {
+            Ok(Ok(response)) => {
+                pool.idle.lock().unwrap().push(process);
+                log::info!("ran `{}` for message {} {}, output {}", self.program, &task.type_name, &task.task, response.trim_end());
+                Ok(HandleResult::from_body(StatusCode::OK, None, response.trim_end()))
+            },
+            Ok(Err(status)) => {
+                log::warn!("`{}` exited while handling message {} {}, {}", self.program, &task.type_name, &task.task, status);
+                self.replace(pool);
+                Ok(self.result(status.code(), "", ""))
+            },
+            Err(e) => {
+                drop(process);
+                self.replace(pool);
+                Err(e)
+            },
+        }
+    }
+
+    /// Starts a process in place of one that's gone, so the pool stays full
+    fn replace(&self, pool: &Pool) {
+        match self.start() {
+            Ok(process) => pool.idle.lock().unwrap().push(process),
+            // Another is started for the next task
+            Err(e) => log::error!("restarting `{}` failed: {:?}", self.program, e),
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for Subprocess {
+    async fn handle(&self, _client: &Client, message_id: &str, task: &DynamicTaskMessage) -> Result<HandleResult> {
+        match &self.pool {
+            Some(pool) => self.run_pooled(pool, task).await,
+            None => self.run(message_id, task).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Client;
+    use serde_json::json;
+
+    use crate::config::Exec;
+    use crate::core::handler::{HandleResult, Handler};
+    use crate::data::DynamicTaskMessage;
+
+    use super::Subprocess;
+
+    async fn handle(subprocess: &Subprocess) -> HandleResult {
+        let task = DynamicTaskMessage::new("task".to_owned(), json!({}));
+        subprocess.handle(&Client::new(), "task:1", &task).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn pooled_process_exiting_is_replaced() {
+        // Responds to one task, then exits with a retryable code while handling the next
+        let script = r#"read line; echo '{"tasks": []}'; read line; exit 75"#;
+        let command = ["sh".to_owned(), "-c".to_owned(), script.to_owned()];
+        let exec: Exec = toml::from_str("pool_size = 1").unwrap();
+        let subprocess = Subprocess::new(&command, &exec).unwrap();
+        assert!(matches!(handle(&subprocess).await, HandleResult::Continue { .. }));
+        assert!(matches!(handle(&subprocess).await, HandleResult::Retry { reason, .. } if reason == "exited with code 75"));
+        assert!(matches!(handle(&subprocess).await, HandleResult::Continue { .. }));
+    }
+
+    #[tokio::test]
+    async fn pooled_process_failing_fails_the_task() {
+        let command = ["sh".to_owned(), "-c".to_owned(), "read line; exit 3".to_owned()];
+        let exec: Exec = toml::from_str("pool_size = 2").unwrap();
+        let subprocess = Subprocess::new(&command, &exec).unwrap();
+        assert!(matches!(handle(&subprocess).await, HandleResult::Fail { reason, .. } if reason == "exited with code 3"));
+    }
+}
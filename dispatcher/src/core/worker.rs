@@ -1,35 +1,474 @@
-use anyhow::Result;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
-use reqwest::{Client, Url};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::{Certificate, Client, Identity, RequestBuilder, Response, StatusCode, Url};
+
+use crate::config::{Body, Http, Retry};
+use crate::core::HandleResult;
+use crate::data::DynamicTaskMessage;
 
-use crate::core::{WorkerResponse, HandleResult};
+use super::breaker::Breaker;
+use super::handler::{excerpt, BatchResponse, TaskStream, WorkerDirective};
 
 use super::handler::Handler;
+use super::signing::Signer;
+
+/// Header telling the worker how many milliseconds it has left before the task's deadline
+pub const TIMEOUT_HEADER: &str = "Trampoline-Timeout-Ms";
+pub const TASK_TYPE_HEADER: &str = "Trampoline-Task-Type";
+/// Header with the task's ID, which stays the same across retries, for idempotency
+pub const TASK_ID_HEADER: &str = "Trampoline-Task-Id";
+/// Header with the ID of the message the task arrived in, which differs between retries
+pub const MESSAGE_ID_HEADER: &str = "Trampoline-Message-Id";
+pub const ATTEMPT_HEADER: &str = "Trampoline-Attempt";
+pub const RUN_ID_HEADER: &str = "Trampoline-Run-Id";
+/// Header with the ID of the task whose handler returned this one, unless it's a root task
+pub const PARENT_ID_HEADER: &str = "Trampoline-Parent-Id";
+/// Header with the URL an asynchronous handler's worker posts its response to, after
+/// accepting the task with 202
+pub const CALLBACK_URL_HEADER: &str = "Trampoline-Callback-Url";
+/// Header with the number of tasks in a batch
+pub const BATCH_SIZE_HEADER: &str = "Trampoline-Batch-Size";
+
+enum Auth {
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+/// `endpoint` with any password replaced, for logs and errors
+fn redact(endpoint: &Url) -> Url {
+    let mut endpoint = endpoint.clone();
+    if endpoint.password().is_some() {
+        let _ = endpoint.set_password(Some("redacted"));
+    }
+    endpoint
+}
 
 pub struct Worker {
-    pub endpoint: Url
+    pub endpoint: Url,
+    /// A client of the worker's own, if its TLS settings differ from the shared client's
+    client: Option<Client>,
+    timeout: Option<Duration>,
+    headers: HeaderMap,
+    auth: Option<Auth>,
+    signer: Option<Signer>,
+    body: Body,
+    /// Statuses retried besides those the response contract retries
+    retryable_statuses: Vec<StatusCode>,
+    /// URL of the Dispatchers' HTTP server, for callbacks
+    callback_url: Option<Url>,
+    breaker: Option<Arc<Breaker>>,
 }
 
-#[async_trait]
-impl Handler for Worker {
-    async fn handle(&self, client: &Client, task: &crate::data::DynamicTaskMessage) -> Result<super::HandleResult> {
-        let body = serde_json::to_string(&task.task)?;
-        let req = client
+impl Worker {
+    /// Reads the secrets and certificates `http` refers to, so that startup fails if any are missing
+    pub fn new(endpoint: Url, http: &Http, retry: Option<&Retry>, callback_url: Option<&Url>) -> Result<Worker> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &http.headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).context(format!("invalid header name `{}`", name))?;
+            let value = HeaderValue::from_str(value).context(format!("invalid value for header `{}`", name))?;
+            headers.insert(name, value);
+        }
+        let auth = match (&http.bearer_token, &http.basic_auth) {
+            (Some(_), Some(_)) => bail!("handler for {} sets both `bearer_token` and `basic_auth`", redact(&endpoint)),
+            (Some(token), None) => Some(Auth::Bearer(token.read()?)),
+            (None, Some(basic)) => Some(Auth::Basic { username: basic.username.clone(), password: basic.password.read()? }),
+            (None, None) => None,
+        };
+        let client = match &http.tls {
+            Some(tls) => {
+                let cert = fs::read(&tls.client_cert).context(format!("reading `{}` failed", tls.client_cert))?;
+                let key = fs::read(&tls.client_key).context(format!("reading `{}` failed", tls.client_key))?;
+                let mut builder = Client::builder()
+                    .identity(Identity::from_pkcs8_pem(&cert, &key).context("invalid client certificate or key")?);
+                if let Some(ca_cert) = &tls.ca_cert {
+                    let ca = fs::read(ca_cert).context(format!("reading `{}` failed", ca_cert))?;
+                    builder = builder.add_root_certificate(Certificate::from_pem(&ca).context(format!("invalid CA certificate `{}`", ca_cert))?);
+                }
+                Some(builder.build()?)
+            },
+            None => None,
+        };
+        Ok(Worker {
+            endpoint,
+            client,
+            timeout: http.timeout_ms.map(Duration::from_millis),
+            headers,
+            auth,
+            signer: http.signing.as_ref().map(Signer::new).transpose()?,
+            body: http.body,
+            retryable_statuses: retry.map(|retry| retry.retryable_statuses.iter()
+                .filter_map(|s| StatusCode::from_u16(*s).ok())
+                .collect())
+                .unwrap_or_default(),
+            callback_url: callback_url.cloned(),
+            breaker: None,
+        })
+    }
+
+    /// Guards calls to the worker with a circuit breaker
+    pub fn with_breaker(self, breaker: Arc<Breaker>) -> Worker {
+        Worker { breaker: Some(breaker), ..self }
+    }
+
+    pub fn is_circuit_open(&self) -> bool {
+        self.breaker.as_ref().is_some_and(|breaker| breaker.is_open())
+    }
+
+    /// Interprets a worker's response. A response body with a `result` decides the result
+    /// whatever the status. Otherwise 2xx is success, 429, 503 and other 5xx are retried, and
+    /// other statuses are failures.
+    fn result(&self, status: StatusCode, retry_after: Option<Duration>, text: &str) -> HandleResult {
+        if serde_json::from_str::<WorkerDirective>(text).is_ok() {
+            return HandleResult::from_body(status, retry_after, text);
+        }
+        // The body often explains a failure, but may be a whole error page
+        let reason = excerpt(text).unwrap_or_else(|| "empty response".to_owned());
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || self.retryable_statuses.contains(&status) {
+            return HandleResult::Retry { status, retry_after, reason };
+        }
+        if !status.is_success() {
+            return HandleResult::Fail { status, reason };
+        }
+        HandleResult::from_body(status, retry_after, text)
+    }
+
+    /// Parses a `Retry-After` header, given either in seconds or as an HTTP date
+    fn retry_after(value: &HeaderValue) -> Option<Duration> {
+        let value = value.to_str().ok()?.trim();
+        match value.parse::<u64>() {
+            Ok(seconds) => Some(Duration::from_secs(seconds)),
+            Err(_) => httpdate::parse_http_date(value).ok()
+                .map(|at| at.duration_since(SystemTime::now()).unwrap_or_default()),
+        }
+    }
+
+    /// A request to the worker with `body`, signed and authenticated, and given no more than
+    /// `remaining` if the tasks it carries have a deadline
+    fn request(&self, client: &Client, body: String, remaining: Option<Duration>) -> Result<RequestBuilder> {
+        let mut req = self.client.as_ref().unwrap_or(client)
             .post(self.endpoint.clone())
-            .header("Content-Type", "application/json")
-            .body(body);
+            .headers(self.headers.clone())
+            .header("Content-Type", "application/json");
+        if let Some(signer) = &self.signer {
+            for (name, value) in signer.sign(body.as_bytes())? {
+                req = req.header(name, value);
+            }
+        }
+        req = self.authenticate(req.body(body));
+        // The remaining time is the request's time budget, if it's less than the timeout
+        let mut timeout = self.timeout;
+        if let Some(remaining) = remaining {
+            if remaining == Duration::ZERO {
+                bail!("deadline passed before the worker was called");
+            }
+            req = req.header(TIMEOUT_HEADER, remaining.as_millis().to_string());
+            timeout = Some(timeout.map_or(remaining, |timeout| timeout.min(remaining)));
+        }
+        if let Some(timeout) = timeout {
+            req = req.timeout(timeout);
+        }
+        Ok(req)
+    }
+
+    fn authenticate(&self, req: RequestBuilder) -> RequestBuilder {
+        // Marked sensitive by reqwest, and never logged
+        match &self.auth {
+            Some(Auth::Bearer(token)) => req.bearer_auth(token),
+            Some(Auth::Basic { username, password }) => req.basic_auth(username, Some(password)),
+            None => req,
+        }
+    }
+
+    /// Whether the worker responds to a GET of `path` on its host with 2xx within `timeout`.
+    /// The request has the worker's headers and credentials, but isn't signed.
+    pub async fn is_healthy(&self, client: &Client, path: &str, timeout: Duration) -> bool {
+        let Ok(url) = self.endpoint.join(path) else {
+            return false;
+        };
+        let req = self.client.as_ref().unwrap_or(client)
+            .get(url)
+            .headers(self.headers.clone())
+            .timeout(timeout);
+        matches!(self.authenticate(req).send().await, Ok(res) if res.status().is_success())
+    }
+
+    /// Interprets a worker's response to a batch of `count` tasks. A 2xx response listing
+    /// `results` gives each task its own, and any other response applies to all of them,
+    /// except that tasks given for a single task would be published once for every task in the
+    /// batch, so such a response fails the batch's tasks instead.
+    fn batch_results(&self, status: StatusCode, retry_after: Option<Duration>, text: &str, count: usize) -> Result<Vec<HandleResult>> {
+        if status.is_success() {
+            if let Ok(batch) = serde_json::from_str::<BatchResponse>(text) {
+                if batch.results.len() != count {
+                    bail!("worker returned {} results for a batch of {} tasks", batch.results.len(), count);
+                }
+                return Ok(batch.results.iter()
+                    .map(|result| HandleResult::from_body(status, retry_after, &result.to_string()))
+                    .collect());
+            }
+        }
+        Ok((0..count).map(|_| match self.result(status, retry_after, text) {
+            HandleResult::Continue { status, response } if !response.tasks.is_empty() => HandleResult::Fail {
+                status,
+                reason: "worker responded to a batch with `tasks` rather than `results`".to_owned(),
+            },
+            result => result,
+        }).collect())
+    }
+
+    /// Whether a response streams its tasks as newline-delimited JSON
+    fn is_streamed(res: &Response) -> bool {
+        res.headers().get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case(TaskStream::CONTENT_TYPE))
+    }
+
+    /// The endpoint without any password in it, for logging
+    pub fn redacted_endpoint(&self) -> Url {
+        redact(&self.endpoint)
+    }
+
+    /// The request carrying `task`. It's built before the breaker is asked, so that failing to,
+    /// such as when the deadline has passed, isn't held against the worker.
+    fn task_request(&self, client: &Client, message_id: &str, task: &DynamicTaskMessage) -> Result<RequestBuilder> {
+        let body = match self.body {
+            Body::Task => serde_json::to_string(&task.task)?,
+            Body::Envelope => serde_json::to_string(task)?,
+        };
+        let mut req = self.request(client, body, task.remaining())?
+            .header(TASK_TYPE_HEADER, &task.type_name)
+            .header(TASK_ID_HEADER, &task.id)
+            .header(MESSAGE_ID_HEADER, message_id)
+            .header(ATTEMPT_HEADER, task.attempt.to_string())
+            .header(RUN_ID_HEADER, task.run_id())
+            .header(ACCEPT, format!("application/json, {}", TaskStream::CONTENT_TYPE));
+        if let Some(parent_id) = &task.parent_id {
+            req = req.header(PARENT_ID_HEADER, parent_id);
+        }
+        if let (Some(callback), Some(callback_url)) = (&task.callback, &self.callback_url) {
+            let url = format!("{}/callbacks/{}", callback_url.as_str().trim_end_matches('/'), callback.token);
+            req = req.header(CALLBACK_URL_HEADER, url);
+        }
+        Ok(req)
+    }
+
+    async fn send(&self, req: RequestBuilder, task: &DynamicTaskMessage) -> Result<HandleResult> {
         let res = req.send().await?;
         let status = res.status();
+        let retry_after = res.headers().get(RETRY_AFTER).and_then(Self::retry_after);
+        let accepted = status == StatusCode::ACCEPTED && task.callback.is_some();
+        if status.is_success() && !accepted && Self::is_streamed(&res) {
+            // A streamed response may be far too large to log
+            log::info!("sent message {} {}, worker {}, receiving streamed tasks {}", &task.type_name, &task.task, self.redacted_endpoint(), status);
+            return Ok(HandleResult::ContinueStream { status, tasks: TaskStream::new(res) });
+        }
         let text = res.text().await?;
-        let parsed_response: Result<WorkerResponse, serde_json::Error> = serde_json::from_str(&text);
-        let result = match parsed_response {
-            Ok(worker_response) => HandleResult::Continue { status, response: worker_response },
-            Err(_) =>
-                // TODO: worker declaration should include a flag that indicates strict response handling, meaning an unparseable response should go to a DLQ
-                HandleResult::ContinueUnparseable { status, text: text.clone() }
-        };
-        
-        log::info!("sent message {} {}, worker {}, received message {} {}", &task.type_name, &task.task, self.endpoint, status, &text);
+        let result = if accepted {
+            HandleResult::Accepted { status }
+        } else {
+            self.result(status, retry_after, &text)
+        };
+
+        log::info!("sent message {} {}, worker {}, received message {} {}", &task.type_name, &task.task, self.redacted_endpoint(), status, &text);
         Ok(result)
     }
-}
\ No newline at end of file
+
+    /// The request carrying the tasks as a JSON array. Since each task's metadata can't go in
+    /// headers, a batch of envelopes carries it instead.
+    fn batch_request(&self, client: &Client, tasks: &[(&str, &DynamicTaskMessage)]) -> Result<RequestBuilder> {
+        let body = match self.body {
+            Body::Task => serde_json::to_string(&tasks.iter().map(|(_, task)| &task.task).collect::<Vec<_>>())?,
+            Body::Envelope => serde_json::to_string(&tasks.iter().map(|(_, task)| task).collect::<Vec<_>>())?,
+        };
+        // The batch has until the earliest of its tasks' deadlines
+        let remaining = tasks.iter().filter_map(|(_, task)| task.remaining()).min();
+        let mut req = self.request(client, body, remaining)?
+            .header(BATCH_SIZE_HEADER, tasks.len().to_string());
+        // A batch's tasks are all of its handler's type
+        if let Some((_, task)) = tasks.first() {
+            req = req.header(TASK_TYPE_HEADER, &task.type_name);
+        }
+        Ok(req)
+    }
+
+    async fn send_batch(&self, req: RequestBuilder, count: usize) -> Result<Vec<HandleResult>> {
+        let res = req.send().await?;
+        let status = res.status();
+        let retry_after = res.headers().get(RETRY_AFTER).and_then(Self::retry_after);
+        let text = res.text().await?;
+        let results = self.batch_results(status, retry_after, &text, count)?;
+
+        log::info!("sent batch of {} messages, worker {}, received message {} {}", count, self.redacted_endpoint(), status, &text);
+        Ok(results)
+    }
+}
+
+#[async_trait]
+impl Handler for Worker {
+    async fn handle(&self, client: &Client, message_id: &str, task: &DynamicTaskMessage) -> Result<HandleResult> {
+        let req = self.task_request(client, message_id, task)?;
+        let Some(breaker) = &self.breaker else {
+            return self.send(req, task).await;
+        };
+        let admitted = match breaker.admit() {
+            Ok(admitted) => admitted,
+            Err(until) => return Ok(HandleResult::CircuitOpen { until }),
+        };
+        let result = self.send(req, task).await;
+        admitted.record(result.as_ref().map_or(true, HandleResult::is_server_error));
+        result
+    }
+
+    async fn handle_batch(&self, client: &Client, tasks: &[(&str, &DynamicTaskMessage)]) -> Result<Vec<HandleResult>> {
+        let req = self.batch_request(client, tasks)?;
+        let Some(breaker) = &self.breaker else {
+            return self.send_batch(req, tasks.len()).await;
+        };
+        let admitted = match breaker.admit() {
+            Ok(admitted) => admitted,
+            Err(until) => return Ok(tasks.iter().map(|_| HandleResult::CircuitOpen { until }).collect()),
+        };
+        let results = self.send_batch(req, tasks.len()).await;
+        admitted.record(results.as_ref().map_or(true, |results| results.iter().any(HandleResult::is_server_error)));
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use reqwest::header::HeaderValue;
+    use reqwest::StatusCode;
+
+    use std::sync::Arc;
+
+    use reqwest::Client;
+
+    use crate::config::{CircuitBreaker, Http, Retry};
+    use crate::core::breaker::Breaker;
+    use crate::core::handler::{HandleResult, Handler};
+    use crate::data::DynamicTaskMessage;
+
+    use super::Worker;
+
+    fn worker() -> Worker {
+        Worker::new("http://localhost:3000/tasks".parse().unwrap(), &Http::default(), None, None).unwrap()
+    }
+
+    /// The kind of result, and for retries how long the worker asked to wait
+    fn kind(result: &HandleResult) -> (&'static str, Option<Duration>) {
+        match result {
+            HandleResult::Continue { .. } => ("continue", None),
+            HandleResult::ContinueStream { .. } => ("stream", None),
+            HandleResult::ContinueUnparseable { .. } => ("unparseable", None),
+            HandleResult::Retry { retry_after, .. } => ("retry", *retry_after),
+            HandleResult::Fail { .. } => ("fail", None),
+            HandleResult::Defer { .. } => ("defer", None),
+            HandleResult::Accepted { .. } => ("accepted", None),
+            HandleResult::CircuitOpen { .. } => ("circuit-open", None),
+        }
+    }
+
+    #[test]
+    fn result_follows_the_status_unless_the_body_has_a_result() {
+        let secs = Duration::from_secs;
+        let cases = [
+            (200, None, r#"{"tasks": []}"#, ("continue", None)),
+            (204, None, "", ("unparseable", None)),
+            (200, None, "ok", ("unparseable", None)),
+            (429, None, "", ("retry", None)),
+            (429, Some(secs(30)), "slow down", ("retry", Some(secs(30)))),
+            (503, Some(secs(5)), "<html>unavailable</html>", ("retry", Some(secs(5)))),
+            (500, None, "oops", ("retry", None)),
+            (400, None, "bad task", ("fail", None)),
+            (404, None, "", ("fail", None)),
+            (409, None, "", ("fail", None)),
+            (200, None, r#"{"result": "fail", "reason": "no such user"}"#, ("fail", None)),
+            (503, None, r#"{"result": "fail"}"#, ("fail", None)),
+            (400, Some(secs(5)), r#"{"result": "retry", "delay": "10s"}"#, ("retry", Some(secs(10)))),
+            (200, Some(secs(5)), r#"{"result": "retry"}"#, ("retry", Some(secs(5)))),
+            (500, None, r#"{"result": "defer", "delay": "1m"}"#, ("defer", None)),
+            (200, None, r#"{"result": "defer"}"#, ("fail", None)),
+        ];
+        let worker = worker();
+        for (status, retry_after, body, expected) in cases {
+            let result = worker.result(StatusCode::from_u16(status).unwrap(), retry_after, body);
+            assert_eq!(kind(&result), expected, "status {} with body {:?}", status, body);
+        }
+    }
+
+    #[test]
+    fn result_retries_configured_statuses() {
+        let retry: Retry = toml::from_str("retryable_statuses = [409]").unwrap();
+        let worker = Worker::new("http://localhost:3000/tasks".parse().unwrap(), &Http::default(), Some(&retry), None).unwrap();
+        assert_eq!(kind(&worker.result(StatusCode::CONFLICT, None, "")), ("retry", None));
+        assert_eq!(kind(&worker.result(StatusCode::BAD_REQUEST, None, "")), ("fail", None));
+    }
+
+    #[test]
+    fn retry_after_is_seconds_or_a_date() {
+        assert_eq!(Worker::retry_after(&HeaderValue::from_static("120")), Some(Duration::from_secs(120)));
+        assert_eq!(Worker::retry_after(&HeaderValue::from_static(" 0 ")), Some(Duration::ZERO));
+        assert_eq!(Worker::retry_after(&HeaderValue::from_static("soon")), None);
+
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let wait = Worker::retry_after(&HeaderValue::from_str(&date).unwrap()).unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60), "waits {:?}", wait);
+
+        let past = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(60));
+        assert_eq!(Worker::retry_after(&HeaderValue::from_str(&past).unwrap()), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn batch_results_are_given_to_each_task_in_order() {
+        let body = r#"{"results": [{"tasks": [{"type": "next", "task": {}}]}, {"result": "fail", "reason": "no such user"}]}"#;
+        let results = worker().batch_results(StatusCode::OK, None, body, 2).unwrap();
+        assert!(matches!(&results[0], HandleResult::Continue { response, .. } if response.tasks.len() == 1));
+        assert!(matches!(&results[1], HandleResult::Fail { reason, .. } if reason == "no such user"));
+    }
+
+    #[test]
+    fn batch_results_must_match_the_batch() {
+        let body = r#"{"results": [{"tasks": []}]}"#;
+        assert!(worker().batch_results(StatusCode::OK, None, body, 2).is_err());
+    }
+
+    #[test]
+    fn batch_response_with_tasks_fails_every_task() {
+        let body = r#"{"tasks": [{"type": "next", "task": {}}]}"#;
+        let results = worker().batch_results(StatusCode::OK, None, body, 3).unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|result| matches!(result, HandleResult::Fail { status: StatusCode::OK, .. })));
+    }
+
+    #[test]
+    fn batch_response_without_results_applies_to_every_task() {
+        let results = worker().batch_results(StatusCode::OK, None, r#"{"result": "retry"}"#, 2).unwrap();
+        assert!(results.iter().all(|result| matches!(result, HandleResult::Retry { .. })));
+
+        let results = worker().batch_results(StatusCode::OK, None, r#"{"tasks": []}"#, 2).unwrap();
+        assert!(results.iter().all(|result| matches!(result, HandleResult::Continue { response, .. } if response.tasks.is_empty())));
+
+        let results = worker().batch_results(StatusCode::BAD_GATEWAY, None, "upstream down", 2).unwrap();
+        assert!(results.iter().all(|result| matches!(result, HandleResult::Retry { status: StatusCode::BAD_GATEWAY, .. })));
+    }
+
+    #[tokio::test]
+    async fn passed_deadline_isnt_held_against_the_worker() {
+        let config = toml::from_str::<CircuitBreaker>("failure_threshold = 1").unwrap();
+        let breaker = Arc::new(Breaker::new("task", "http://localhost:3000/tasks".parse().unwrap(), &config));
+        let worker = worker().with_breaker(breaker.clone());
+        let mut task: DynamicTaskMessage = serde_json::from_str(r#"{"type": "task", "task": {}}"#).unwrap();
+        task.deadline = Some(SystemTime::now() - Duration::from_secs(1));
+        let error = worker.handle(&Client::new(), "task:1", &task).await.err().unwrap();
+        assert!(error.to_string().contains("deadline passed"));
+        assert!(!breaker.is_open());
+    }
+}
@@ -1,37 +1,222 @@
-use pulsar::{producer, DeserializeMessage, Error as PulsarError, Payload, SerializeMessage};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The envelope every task travels in. Only `type` and `task` are required, so that tasks
+/// in the original two-field format, or submitted by hand, still deserialize.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DynamicTaskMessage {
     #[serde(rename = "type")]
     pub type_name: String,
-    // FUTURE: scheduling options go here
     pub task: Value,
+    /// Delivers the task no earlier than this time, given as an RFC 3339 timestamp
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "super::rfc3339::option")]
+    pub deliver_at: Option<SystemTime>,
+    /// Delivers the task this long after it's published, e.g. `"3days"` or `"90s"`
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "humantime_serde")]
+    pub delay: Option<Duration>,
+    /// The task is skipped, and sent to the expired topic, if it isn't handled by this time,
+    /// given as an RFC 3339 timestamp
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "super::rfc3339::option")]
+    pub deadline: Option<SystemTime>,
+    /// A deadline relative to `created_at`, e.g. `"1h"`
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "humantime_serde")]
+    pub ttl: Option<Duration>,
+    /// Identifies the task, and stays the same across its retries
+    #[serde(default = "DynamicTaskMessage::new_id")]
+    pub id: String,
+    /// When the task was first created, as an RFC 3339 timestamp
+    #[serde(default = "SystemTime::now", with = "super::rfc3339")]
+    pub created_at: SystemTime,
+    /// 1-based count of the attempts made at handling this task, carried on the message
+    /// so that retries are counted across redeliveries and restarts
+    #[serde(default = "DynamicTaskMessage::first_attempt")]
+    pub attempt: u32,
+    /// Free-form metadata about the task, separate from the task itself
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    /// Correlates all the tasks that descend from the same root task. Unset on a root task,
+    /// whose own ID serves as the run ID; see `run_id()`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    /// ID of the task whose handler returned this one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    /// Set while an asynchronous handler's worker has the task, see `AwaitingCallback`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback: Option<AwaitingCallback>,
 }
 
-impl DeserializeMessage for DynamicTaskMessage {
-    type Output = Result<DynamicTaskMessage, serde_json::Error>;
+/// A worker's callback that a task is waiting on. A task message carrying one isn't handled
+/// again, but either brings the worker's response or checks whether the callback timed out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AwaitingCallback {
+    /// Identifies the callback, and is unguessable since it's all a worker needs to complete it
+    pub token: String,
+    /// The body of the worker's callback, once it has arrived
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<Value>,
+}
 
-    fn deserialize_message(payload: &Payload) -> Self::Output {
-        serde_json::from_slice(&payload.data)
+impl AwaitingCallback {
+    /// A callback with a new token
+    pub fn issue() -> AwaitingCallback {
+        AwaitingCallback { token: uuid::Uuid::new_v4().to_string(), response: None }
     }
 }
 
-/* SerializeMessage takes input as move by default to avoid copies:
-     https://github.com/streamnative/pulsar-rs/pull/109
-
-   Here, since serde_json::to_vec takes a reference anyway, it doesn't matter
-   and we impl on the more natural reference type
- */
-impl SerializeMessage for &DynamicTaskMessage {
-    fn serialize_message(input: Self) -> Result<producer::Message, PulsarError> {
-        let payload =
-            serde_json::to_vec(input).map_err(|e| PulsarError::Custom(e.to_string()))?;
-        Ok(producer::Message {
-            payload,
-            ..Default::default()
-        })
+impl DynamicTaskMessage {
+    pub fn new(type_name: String, task: Value) -> DynamicTaskMessage {
+        DynamicTaskMessage {
+            type_name,
+            task,
+            deliver_at: None,
+            delay: None,
+            deadline: None,
+            ttl: None,
+            id: Self::new_id(),
+            created_at: SystemTime::now(),
+            attempt: Self::first_attempt(),
+            headers: HashMap::new(),
+            run_id: None,
+            parent_id: None,
+            callback: None,
+        }
+    }
+
+    fn new_id() -> String { uuid::Uuid::new_v4().to_string() }
+
+    fn first_attempt() -> u32 { 1 }
+
+    /// The run this task belongs to
+    pub fn run_id(&self) -> &str {
+        self.run_id.as_deref().unwrap_or(&self.id)
+    }
+
+    /// Clears the task's delivery schedule, so it's only delayed once, returning when it's due.
+    /// If both `deliver_at` and `delay` are set the later of the two applies.
+    pub fn take_schedule(&mut self) -> Option<SystemTime> {
+        let delayed = self.delay.take().map(|delay| SystemTime::now() + delay);
+        match (self.deliver_at.take(), delayed) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// When the task expires, the earlier of its `deadline` and its `ttl` after creation
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        let ttl = self.ttl.map(|ttl| self.created_at + ttl);
+        match (self.deadline, ttl) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// How long is left to handle the task before it expires, if it has a deadline. Zero
+    /// once the deadline has passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.expires_at().map(|at| at.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+
+    /// A copy of this task for its next attempt
+    pub fn next_attempt(&self) -> DynamicTaskMessage {
+        DynamicTaskMessage { attempt: self.attempt + 1, ..self.clone() }
+    }
+
+    /// Makes a task returned by this task's handler a child of this task, in the same run
+    /// unless it names a run of its own
+    pub fn adopt(&self, mut child: DynamicTaskMessage) -> DynamicTaskMessage {
+        // Only the Dispatcher makes a task wait on a callback
+        child.callback = None;
+        child.run_id = Some(child.run_id.unwrap_or_else(|| self.run_id().to_owned()));
+        child.parent_id = Some(self.id.clone());
+        child
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde_json::json;
+
+    use super::{AwaitingCallback, DynamicTaskMessage};
+
+    #[test]
+    fn two_field_format_gets_defaults() {
+        let task: DynamicTaskMessage = serde_json::from_str(r#"{"type": "send-email", "task": {"to": "a@example.com"}}"#).unwrap();
+        assert_eq!(task.type_name, "send-email");
+        assert_eq!(task.task, json!({"to": "a@example.com"}));
+        assert_eq!(task.attempt, 1);
+        assert!(!task.id.is_empty());
+        assert!(task.headers.is_empty());
+        assert_eq!(task.run_id, None);
+        assert_eq!(task.parent_id, None);
+        assert_eq!(task.run_id(), task.id);
+        assert!(task.deliver_at.is_none() && task.deadline.is_none() && task.callback.is_none());
+
+        let other: DynamicTaskMessage = serde_json::from_str(r#"{"type": "send-email", "task": {}}"#).unwrap();
+        assert_ne!(task.id, other.id);
+    }
+
+    #[test]
+    fn envelope_round_trips() {
+        let task = DynamicTaskMessage::new("send-email".to_owned(), json!({})).next_attempt();
+        let json = serde_json::to_string(&task).unwrap();
+        let parsed: DynamicTaskMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.id, task.id);
+        assert_eq!(parsed.attempt, 2);
+    }
+
+    #[test]
+    fn deliver_at_takes_any_offset() {
+        let task: DynamicTaskMessage = serde_json::from_str(r#"{"type": "send-email", "task": {}, "deliver_at": "2024-04-15T04:00:00+02:00"}"#).unwrap();
+        assert_eq!(task.deliver_at, Some(UNIX_EPOCH + Duration::from_secs(1713146400)));
+        let json = serde_json::to_value(&task).unwrap();
+        assert_eq!(json["deliver_at"], "2024-04-15T02:00:00Z");
+    }
+
+    #[test]
+    fn deadline_takes_any_offset() {
+        let task: DynamicTaskMessage = serde_json::from_str(r#"{"type": "send-email", "task": {}, "deadline": "2024-04-14T21:30:00.5-04:30"}"#).unwrap();
+        assert_eq!(task.deadline, Some(UNIX_EPOCH + Duration::from_millis(1713146400500)));
+        assert_eq!(task.expires_at(), task.deadline);
+        let json = serde_json::to_value(&task).unwrap();
+        assert_eq!(json["deadline"], "2024-04-15T02:00:00.500Z");
+    }
+
+    #[test]
+    fn created_at_takes_any_offset() {
+        let task: DynamicTaskMessage = serde_json::from_str(r#"{"type": "send-email", "task": {}, "created_at": "2024-04-15T02:00:00+00:00", "ttl": "1h"}"#).unwrap();
+        assert_eq!(task.created_at, UNIX_EPOCH + Duration::from_secs(1713146400));
+        assert_eq!(task.expires_at(), Some(task.created_at + Duration::from_secs(3600)));
+        let json = serde_json::to_value(&task).unwrap();
+        assert_eq!(json["created_at"], "2024-04-15T02:00:00Z");
+    }
+
+    #[test]
+    fn children_join_the_parents_run() {
+        let root = DynamicTaskMessage::new("fetch-users".to_owned(), json!({}));
+        let child = root.adopt(DynamicTaskMessage::new("send-email".to_owned(), json!({})));
+        assert_eq!(child.run_id.as_deref(), Some(root.id.as_str()));
+        assert_eq!(child.parent_id.as_deref(), Some(root.id.as_str()));
+
+        let grandchild = child.adopt(DynamicTaskMessage::new("log-email".to_owned(), json!({})));
+        assert_eq!(grandchild.run_id(), root.id);
+        assert_eq!(grandchild.parent_id.as_deref(), Some(child.id.as_str()));
+    }
+
+    #[test]
+    fn children_keep_their_own_run_and_no_callback() {
+        let root = DynamicTaskMessage::new("fetch-users".to_owned(), json!({}));
+        let mut child = DynamicTaskMessage::new("send-email".to_owned(), json!({}));
+        child.run_id = Some("other-run".to_owned());
+        child.callback = Some(AwaitingCallback::issue());
+        let child = root.adopt(child);
+        assert_eq!(child.run_id(), "other-run");
+        assert_eq!(child.parent_id.as_deref(), Some(root.id.as_str()));
+        assert!(child.callback.is_none());
+    }
+}
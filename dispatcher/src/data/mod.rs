@@ -1,3 +1,8 @@
+#[allow(clippy::module_inception)]
 mod data;
+/// Serializes times as RFC 3339 timestamps, taking any UTC offset, where `humantime_serde`
+/// only takes timestamps in UTC ending in `Z`
+pub mod rfc3339;
 
-pub use data::DynamicTaskMessage;
\ No newline at end of file
+pub use data::DynamicTaskMessage;
+pub use data::AwaitingCallback;
\ No newline at end of file
@@ -0,0 +1,41 @@
+use std::time::SystemTime;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Deserializer, Serializer};
+
+fn parse<E: serde::de::Error>(timestamp: &str) -> Result<SystemTime, E> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(SystemTime::from)
+        .map_err(|e| E::custom(format!("invalid RFC 3339 timestamp `{}`: {}", timestamp, e)))
+}
+
+fn format(time: &SystemTime) -> String {
+    DateTime::<Utc>::from(*time).to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// For `SystemTime` fields, with `#[serde(with = "rfc3339")]`
+pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(time))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+    parse(&String::deserialize(deserializer)?)
+}
+
+/// For `Option<SystemTime>` fields, with `#[serde(default, with = "rfc3339::option")]`
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => serializer.serialize_some(&format(time)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SystemTime>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|timestamp| parse(&timestamp))
+            .transpose()
+    }
+}
@@ -0,0 +1,496 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use futures::stream::{FuturesUnordered, TryStreamExt};
+use reqwest::StatusCode;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::callback::Callbacks;
+use crate::config::Delivery;
+use crate::core::{Forwarder, HandleResult, RetryPolicy};
+use crate::data::{AwaitingCallback, DynamicTaskMessage};
+use crate::mq::{MessageQueue, Received};
+use crate::producer::Producer;
+
+/// Most tasks from a worker's streamed response being published at once
+const MAX_STREAM_SENDS: usize = 64;
+
+/// Consumes task messages and processes them concurrently, up to a bound on in-flight tasks
+pub struct Dispatcher {
+    mq: Arc<dyn MessageQueue>,
+    forwarder: Forwarder,
+    producer: Producer,
+    callbacks: Callbacks,
+    dead_letter_topic: Option<String>,
+    expired_topic: Option<String>,
+    /// A permit for each task received and not yet finished, which is taken before receiving
+    received: Arc<Semaphore>,
+    /// A permit for each task being handled, other than those waiting on their handler's own limits
+    in_flight: Arc<Semaphore>,
+}
+
+/// What's left to do with a message once its task has been handled
+enum Outcome {
+    Done,
+    /// The task can't be processed and should go to the dead-letter topic, for the given reason
+    DeadLetter(String),
+    /// The task failed and should be attempted again no earlier than the given time
+    Retry(SystemTime),
+    /// The task should be handled again at the given time, as the same attempt
+    Defer(SystemTime),
+    /// The worker wasn't called because its circuit breaker is open, and the task should be
+    /// handled no earlier than the given time, as the same attempt
+    CircuitOpen(SystemTime),
+    /// The worker accepted the task, and will respond through its callback
+    Accepted,
+}
+
+/// How a processed message is settled with the queue
+enum Settlement {
+    Ack,
+    Nack,
+    Term,
+}
+
+impl Dispatcher {
+    pub fn new(mq: Arc<dyn MessageQueue>, forwarder: Forwarder, callbacks: Callbacks, dead_letter_topic: Option<String>, expired_topic: Option<String>, max_in_flight: usize, max_waiting: usize) -> Dispatcher {
+        let producer = Producer::new(mq.clone());
+        let received = Arc::new(Semaphore::new(max_in_flight + max_waiting));
+        let in_flight = Arc::new(Semaphore::new(max_in_flight));
+        Dispatcher { mq, forwarder, producer, callbacks, dead_letter_topic, expired_topic, received, in_flight }
+    }
+
+    /// Runs until the queue is closed, returning the number of messages received. Messages
+    /// received by then are processed before it returns, even if receiving failed.
+    pub async fn run(self) -> Result<usize> {
+        let this = Arc::new(self);
+        let mut tasks = JoinSet::new();
+
+        let mut counter = 0usize;
+        let result = loop {
+            let permit = this.received.clone().acquire_owned().await?;
+            while let Some(joined) = tasks.try_join_next() {
+                Self::log_panic(joined);
+            }
+            let msg = match this.mq.receive().await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break Ok(counter),
+                Err(e) => break Err(e),
+            };
+            let this = this.clone();
+            tasks.spawn(async move { this.process_message(msg, permit).await });
+            counter += 1;
+        };
+        while let Some(joined) = tasks.join_next().await {
+            Self::log_panic(joined);
+        }
+        result
+    }
+
+    fn log_panic(joined: Result<(), tokio::task::JoinError>) {
+        if let Err(e) = joined {
+            log::error!("processing a message panicked: {:?}", e);
+        }
+    }
+
+    /// Processes one message and settles it according to its handler's delivery mode. `_permit`
+    /// is the message's place among the tasks received, which it keeps until it's settled.
+    async fn process_message(&self, msg: Received, _permit: OwnedSemaphorePermit) {
+        let message_id = &msg.message_id;
+        let mut data = match serde_json::from_slice::<DynamicTaskMessage>(&msg.payload) {
+            Ok(data) => data,
+            Err(e) => {
+                let reason = format!("could not deserialize message: {}", e);
+                if let Err(e) = self.dead_letter(&msg, &reason).await {
+                    log::error!("messageId:<{}> could not be dead-lettered: {:?}", message_id, e);
+                }
+                self.settle(&msg, Settlement::Term).await;
+                return;
+            }
+        };
+        data.attempt += msg.redeliveries;
+
+        let handler_config = self.forwarder.handler_config(&data);
+        let delivery = handler_config.map(|c| c.delivery).unwrap_or_default();
+        let retry_policy = handler_config.and_then(|c| c.retry.as_ref()).map(RetryPolicy::new);
+        let strict = handler_config.is_some_and(|c| c.strict);
+        let callback_timeout = handler_config.and_then(|c| c.callback.as_ref()).map(|c| Duration::from_millis(c.timeout_ms));
+        if delivery == Delivery::AtMostOnce {
+            self.settle(&msg, Settlement::Ack).await;
+        }
+
+        // The task was handled already, so its deadline doesn't apply
+        let callback = data.callback.take();
+        if let Some(expired_at) = data.expires_at().filter(|at| callback.is_none() && *at <= SystemTime::now()) {
+            let result = self.expire(&msg, expired_at).await;
+            match (result, delivery) {
+                (Ok(()), Delivery::AtLeastOnce) => self.settle(&msg, Settlement::Term).await,
+                (Ok(()), Delivery::AtMostOnce) => {},
+                (Err(e), _) => {
+                    log::error!("messageId:<{}> could not be sent to the expired topic: {:?}", message_id, e);
+                    if delivery == Delivery::AtLeastOnce {
+                        self.settle(&msg, Settlement::Nack).await;
+                    }
+                },
+            }
+            return;
+        }
+
+        let called_back = callback.is_some();
+        let outcome = match callback {
+            Some(callback) => {
+                let _permit = self.in_flight.acquire().await;
+                self.called_back(retry_policy.as_ref(), strict, delivery, message_id, &data, callback).await
+            },
+            None => loop {
+                // A task takes its place in flight only once its handler's own limits let it
+                // through, so that a saturated or rate-limited handler doesn't hold up other
+                // handlers' tasks beyond the waiting room left by `max_waiting`
+                let outcome = match self.forwarder.wait_turn(&data).await {
+                    Ok(_turn) => {
+                        let _permit = self.in_flight.acquire().await;
+                        self.call(retry_policy.as_ref(), strict, callback_timeout, message_id, &data).await
+                    },
+                    Err(e) => self.retry(retry_policy.as_ref(), message_id, &data, e, None).await,
+                };
+                // While the breaker is open the task waits here, rather than going back to the
+                // queue, and the queue stops delivering its topic where it can
+                match outcome {
+                    Ok(Outcome::CircuitOpen(until)) => {
+                        self.mq.pause(&msg.topic, until);
+                        tokio::time::sleep(until.duration_since(SystemTime::now()).unwrap_or_default()).await;
+                    },
+                    outcome => break outcome,
+                }
+            },
+        };
+        // The settlement still to make, if the message isn't settled already
+        let result = match outcome {
+            Ok(Outcome::Done | Outcome::Accepted) => Ok(Some(Settlement::Ack)),
+            Ok(Outcome::DeadLetter(reason)) => self.dead_letter(&msg, &reason).await.map(|()| Some(Settlement::Term)),
+            // An unsettled message is redelivered by the queue itself, which may do so natively.
+            // A message about a callback is published afresh instead, since redelivering it
+            // wouldn't call the worker again.
+            Ok(Outcome::Retry(deliver_at)) if delivery == Delivery::AtLeastOnce && !called_back => {
+                self.producer.redeliver_at(&msg, &data.next_attempt(), deliver_at).await.map(|()| None)
+            },
+            Ok(Outcome::Retry(deliver_at)) => self.producer.send_at(&data.next_attempt(), deliver_at).await.map(|()| Some(Settlement::Ack)),
+            // Republished rather than redelivered, since a redelivery counts as an attempt on some
+            // queues. A task about a callback doesn't call the worker, so its breaker can't be open.
+            Ok(Outcome::Defer(deliver_at) | Outcome::CircuitOpen(deliver_at)) => {
+                self.producer.send_at(&data, deliver_at).await.map(|()| Some(Settlement::Ack))
+            },
+            Err(e) => Err(e),
+        };
+        match (result, delivery) {
+            (Ok(Some(settlement)), Delivery::AtLeastOnce) => self.settle(&msg, settlement).await,
+            (Err(e), Delivery::AtLeastOnce) => {
+                log::error!("messageId:<{}> task:<{}> failed, requesting redelivery: {:?}", message_id, &data.type_name, e);
+                self.settle(&msg, Settlement::Nack).await;
+            },
+            (Err(e), Delivery::AtMostOnce) => {
+                log::error!("messageId:<{}> task:<{}> failed: {:?}", message_id, &data.type_name, e);
+            },
+            (Ok(_), _) => {},
+        }
+    }
+
+    /// Acks, nacks or terminates a message. Failures are only logged, since the queue
+    /// redelivers messages that are never acked.
+    async fn settle(&self, msg: &Received, settlement: Settlement) {
+        let (result, action) = match settlement {
+            Settlement::Ack => (self.mq.ack(msg).await, "acked"),
+            Settlement::Nack => (self.mq.nack(msg).await, "nacked"),
+            Settlement::Term => (self.mq.term(msg).await, "terminated"),
+        };
+        if let Err(e) = result {
+            log::error!("messageId:<{}> could not be {}: {:?}", msg.message_id, action, e);
+        }
+    }
+
+    /// Has the task's handler process it, and deals with the result. An asynchronous
+    /// handler's worker is given a callback, which it may use by accepting the task.
+    async fn call(&self, retry_policy: Option<&RetryPolicy>, strict: bool, callback_timeout: Option<Duration>, message_id: &str, data: &DynamicTaskMessage) -> Result<Outcome> {
+        let Some(timeout) = callback_timeout else {
+            return self.forward(retry_policy, strict, message_id, data).await;
+        };
+        let callback = AwaitingCallback::issue();
+        let sent = DynamicTaskMessage { callback: Some(callback.clone()), ..data.clone() };
+        let timeout_at = self.callbacks.register(&sent, &callback, timeout).await?;
+        match self.forward(retry_policy, strict, message_id, &sent).await {
+            Ok(Outcome::Accepted) => {
+                self.callbacks.expect(&sent, timeout_at).await?;
+                Ok(Outcome::Accepted)
+            },
+            outcome => {
+                self.callbacks.cancel(&callback).await;
+                outcome
+            },
+        }
+    }
+
+    /// Processes a message about a task waiting on its worker's callback: either the task with
+    /// the callback's response, or the task checking whether the callback timed out
+    async fn called_back(&self, retry_policy: Option<&RetryPolicy>, strict: bool, delivery: Delivery, message_id: &str, data: &DynamicTaskMessage, callback: AwaitingCallback) -> Result<Outcome> {
+        if let Some(response) = callback.response {
+            let result = HandleResult::from_body(StatusCode::OK, None, &response.to_string());
+            return match self.handle_result(retry_policy, strict, message_id, data, Some(result)).await {
+                Ok(outcome) => Ok(outcome),
+                Err(e) => self.retry(retry_policy, message_id, data, e, None).await,
+            };
+        }
+        if !self.callbacks.timed_out(&callback).await? {
+            return Ok(Outcome::Done);
+        }
+        let error = anyhow!("worker didn't call back in time");
+        // Attempted again right away without a retry policy under at-least-once delivery, as if
+        // the message had been redelivered
+        let retry_after = (delivery == Delivery::AtLeastOnce).then_some(Duration::ZERO);
+        self.retry(retry_policy, message_id, data, error, retry_after).await
+    }
+
+    /// Processes a task, retrying it if that fails
+    async fn forward(&self, retry_policy: Option<&RetryPolicy>, strict: bool, message_id: &str, data: &DynamicTaskMessage) -> Result<Outcome> {
+        let result = match self.forwarder.process(message_id, data).await {
+            Ok(result) => result,
+            Err(e) => return self.retry(retry_policy, message_id, data, e, None).await,
+        };
+        match self.handle_result(retry_policy, strict, message_id, data, result).await {
+            Ok(outcome) => Ok(outcome),
+            Err(e) => self.retry(retry_policy, message_id, data, e, None).await,
+        }
+    }
+
+    /// Deals with a handler's result and republishes the tasks that result from it. Returns
+    /// only once the producer has confirmed every republished task.
+    async fn handle_result(&self, retry_policy: Option<&RetryPolicy>, strict: bool, message_id: &str, data: &DynamicTaskMessage, result: Option<HandleResult>) -> Result<Outcome> {
+        match result {
+            Some(HandleResult::Continue { status, response }) => {
+                let tasks: Vec<_> = response.tasks.into_iter().map(|t| data.adopt(t)).collect();
+                futures::future::try_join_all(tasks.iter().map(|t| self.producer.send(t))).await?;
+                let plural = if tasks.len() == 1 { "task" } else { "tasks" };
+                log::info!("messageId:<{}> task:<{}> status:<{}> result:<Continue:{} new {}>", message_id, &data.type_name, status, tasks.len(), plural);
+            },
+            Some(HandleResult::ContinueStream { status, mut tasks }) => {
+                // The next task is read only while fewer than MAX_STREAM_SENDS are unconfirmed,
+                // which holds back a worker streaming faster than the queue accepts its tasks
+                let mut sends = FuturesUnordered::new();
+                let mut count = 0;
+                while let Some(task) = tasks.next().await? {
+                    if sends.len() >= MAX_STREAM_SENDS {
+                        sends.try_next().await?;
+                    }
+                    let task = data.adopt(task);
+                    let producer = &self.producer;
+                    sends.push(async move { producer.send(&task).await });
+                    count += 1;
+                }
+                while sends.try_next().await?.is_some() {}
+                let plural = if count == 1 { "task" } else { "tasks" };
+                log::info!("messageId:<{}> task:<{}> status:<{}> result:<Continue:{} new streamed {}>", message_id, &data.type_name, status, count, plural);
+            },
+            Some(HandleResult::ContinueUnparseable { status, text }) if strict => {
+                log::info!("got message {} {}, result: {} {}", &data.type_name, &data.task, status, text);
+                return Ok(Outcome::DeadLetter(format!("unparseable worker response with status {}", status)));
+            },
+            Some(HandleResult::ContinueUnparseable { status, text }) => {
+                log::info!("got message {} {}, result: {} {}", &data.type_name, &data.task, status, text);
+
+            },
+            Some(HandleResult::Retry { status, retry_after, reason }) => {
+                let error = anyhow!("worker asked for a retry with status {}: {}", status, reason);
+                return self.retry(retry_policy, message_id, data, error, retry_after).await;
+            },
+            Some(HandleResult::Fail { status, reason }) => {
+                log::info!("messageId:<{}> task:<{}> status:<{}> result:<Fail:{}>", message_id, &data.type_name, status, reason);
+                return Ok(Outcome::DeadLetter(format!("worker failed the task with status {}: {}", status, reason)));
+            },
+            Some(HandleResult::Defer { status, until }) => {
+                log::info!("messageId:<{}> task:<{}> status:<{}> result:<Defer:{}>", message_id, &data.type_name, status, DateTime::<Utc>::from(until).to_rfc3339());
+                return Ok(Outcome::Defer(until));
+            },
+            Some(HandleResult::Accepted { status }) => {
+                log::info!("messageId:<{}> task:<{}> status:<{}> result:<Accepted>", message_id, &data.type_name, status);
+                return Ok(Outcome::Accepted);
+            },
+            Some(HandleResult::CircuitOpen { until }) => {
+                log::info!("messageId:<{}> task:<{}> result:<CircuitOpen:{}>", message_id, &data.type_name, DateTime::<Utc>::from(until).to_rfc3339());
+                return Ok(Outcome::CircuitOpen(until));
+            },
+            None => {
+                log::info!("could not find worker for {} {}", &data.type_name, &data.task);
+                return Ok(Outcome::DeadLetter("no matching handler".to_owned()));
+            }
+        };
+        Ok(Outcome::Done)
+    }
+
+    /// Deals with a failed task according to its handler's retry policy, either by scheduling
+    /// a later attempt or by giving up on it. The next attempt is no sooner than `retry_after`,
+    /// if the worker asked for one. Returns the error when the handler has no retry policy and
+    /// the worker gave no time, so that the failure is left to the delivery mode.
+    async fn retry(&self, retry_policy: Option<&RetryPolicy>, message_id: &str, data: &DynamicTaskMessage, error: anyhow::Error, retry_after: Option<Duration>) -> Result<Outcome> {
+        let policy = match (retry_policy, retry_after) {
+            (Some(policy), _) => policy,
+            (None, Some(retry_after)) => {
+                log::warn!("messageId:<{}> task:<{}> attempt:<{}> failed, retrying in {:?}: {:?}", message_id, &data.type_name, data.attempt, retry_after, error);
+                return Ok(Outcome::Retry(SystemTime::now() + retry_after));
+            },
+            (None, None) => return Err(error),
+        };
+        if !policy.should_retry(data.attempt) {
+            log::error!("messageId:<{}> task:<{}> attempt:<{}> failed, giving up: {:?}", message_id, &data.type_name, data.attempt, error);
+            return Ok(Outcome::DeadLetter(format!("failed after {} attempts: {:#}", data.attempt, error)));
+        }
+        let backoff = policy.delay(data.attempt, retry_after);
+        log::warn!("messageId:<{}> task:<{}> attempt:<{}> failed, retrying in {:?}: {:?}", message_id, &data.type_name, data.attempt, backoff, error);
+        Ok(Outcome::Retry(SystemTime::now() + backoff))
+    }
+
+    /// Publishes the original payload of a message that can't be processed to the dead-letter
+    /// topic, with the reason and where it came from added to its properties
+    async fn dead_letter(&self, msg: &Received, reason: &str) -> Result<()> {
+        let message_id = &msg.message_id;
+        let topic = match &self.dead_letter_topic {
+            Some(topic) => topic,
+            None => {
+                log::warn!("messageId:<{}> dropped, no dead-letter topic configured: {}", message_id, reason);
+                return Ok(());
+            }
+        };
+        let mut properties = msg.properties.clone();
+        properties.extend([
+            ("dead-letter-reason".to_owned(), reason.to_owned()),
+            ("original-topic".to_owned(), msg.topic.clone()),
+            ("original-message-id".to_owned(), message_id.clone()),
+        ]);
+        self.producer.send_raw(topic, msg.payload.clone(), properties).await?;
+        log::info!("messageId:<{}> sent to dead-letter topic {}: {}", message_id, topic, reason);
+        Ok(())
+    }
+
+    /// Publishes the original payload of a message whose deadline passed before it was handled
+    /// to the expired topic, with its properties, instead of handling it
+    async fn expire(&self, msg: &Received, expired_at: SystemTime) -> Result<()> {
+        let message_id = &msg.message_id;
+        let expired_at = DateTime::<Utc>::from(expired_at).to_rfc3339();
+        let topic = match &self.expired_topic {
+            Some(topic) => topic,
+            None => {
+                log::warn!("messageId:<{}> dropped, expired at {} and no expired topic configured", message_id, expired_at);
+                return Ok(());
+            }
+        };
+        let mut properties = msg.properties.clone();
+        properties.extend([
+            ("expired-at".to_owned(), expired_at.clone()),
+            ("original-topic".to_owned(), msg.topic.clone()),
+            ("original-message-id".to_owned(), message_id.clone()),
+        ]);
+        self.producer.send_raw(topic, msg.payload.clone(), properties).await?;
+        log::info!("messageId:<{}> expired at {}, sent to expired topic {}", message_id, expired_at, topic);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use reqwest::Client;
+    use serde::Deserialize;
+
+    use crate::callback::Callbacks;
+    use crate::config::TaskHandler;
+    use crate::core::{Forwarder, HandlerRepo};
+    use crate::data::DynamicTaskMessage;
+    use crate::mq::MemoryQueue;
+    use crate::producer::Producer;
+
+    use super::Dispatcher;
+
+    const DEAD_LETTER_TOPIC: &str = "dead-letter";
+
+    const CHILD_TASK: &str = r#"echo '{"tasks": [{"type": "child", "task": {}}]}'"#;
+
+    #[derive(Deserialize)]
+    struct Handlers {
+        handlers: Vec<TaskHandler>,
+    }
+
+    /// Dispatches one task to a handler that runs `script` in a shell, configured by the
+    /// `handler` TOML, until `done` holds of the queue
+    async fn dispatch(handler: &str, script: &str, done: impl Fn(&MemoryQueue) -> bool) -> Arc<MemoryQueue> {
+        dispatch_tasks(handler, script, 1, done).await
+    }
+
+    /// Dispatches `tasks` tasks, like `dispatch`, with room for 4 in flight and 4 waiting
+    async fn dispatch_tasks(handler: &str, script: &str, tasks: usize, done: impl Fn(&MemoryQueue) -> bool) -> Arc<MemoryQueue> {
+        let config = format!("[[handlers]]\ntask_selector = {{ type = \"task\" }}\ncommand = [\"sh\", \"-c\", {:?}]\n{}", script, handler);
+        let config: Handlers = toml::from_str(&config).unwrap();
+        let mq = Arc::new(MemoryQueue::new(&["task".to_owned()]));
+        let client = Client::new();
+        let handlers = HandlerRepo::new(&config.handlers, None, &client, mq.clone()).unwrap();
+        let dispatcher = Dispatcher::new(mq.clone(), Forwarder::new(client, handlers), Callbacks::new(mq.clone()), Some(DEAD_LETTER_TOPIC.to_owned()), None, 4, 4);
+
+        let task: DynamicTaskMessage = serde_json::from_str(r#"{"type": "task", "task": {}}"#).unwrap();
+        for _ in 0..tasks {
+            Producer::new(mq.clone()).send(&task).await.unwrap();
+        }
+        let running = tokio::spawn(dispatcher.run());
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !done(&mq) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("the task wasn't processed in time");
+        running.abort();
+        mq
+    }
+
+    fn attempt(payload: &[u8]) -> u32 {
+        serde_json::from_slice::<DynamicTaskMessage>(payload).unwrap().attempt
+    }
+
+    #[tokio::test]
+    async fn acks_after_success() {
+        let mq = dispatch("delivery = \"at-least-once\"", CHILD_TASK, |mq| mq.waiting("child").len() == 1 && mq.unacked() == 0).await;
+        assert!(mq.waiting("task").is_empty());
+        assert!(mq.waiting(DEAD_LETTER_TOPIC).is_empty());
+    }
+
+    #[tokio::test]
+    async fn nacked_task_is_redelivered_as_the_next_attempt() {
+        let script = format!("[ \"$TRAMPOLINE_ATTEMPT\" -ge 2 ] || exit 75; {}", CHILD_TASK);
+        let mq = dispatch("delivery = \"at-least-once\"", &script, |mq| mq.waiting("child").len() == 1 && mq.unacked() == 0).await;
+        assert!(mq.waiting("task").is_empty());
+        assert!(mq.waiting(DEAD_LETTER_TOPIC).is_empty());
+    }
+
+    #[tokio::test]
+    async fn dead_letters_after_the_last_attempt() {
+        let handler = "delivery = \"at-least-once\"\nretry = { max_attempts = 2, initial_backoff_ms = 10 }";
+        let mq = dispatch(handler, "exit 75", |mq| !mq.waiting(DEAD_LETTER_TOPIC).is_empty() && mq.unacked() == 0).await;
+        let dead_letters = mq.waiting(DEAD_LETTER_TOPIC);
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(attempt(&dead_letters[0]), 2);
+        assert!(mq.waiting("task").is_empty());
+    }
+
+    #[tokio::test]
+    async fn dead_letters_a_failed_task() {
+        let mq = dispatch("delivery = \"at-least-once\"", "exit 1", |mq| !mq.waiting(DEAD_LETTER_TOPIC).is_empty() && mq.unacked() == 0).await;
+        assert_eq!(attempt(&mq.waiting(DEAD_LETTER_TOPIC)[0]), 1);
+    }
+
+    #[tokio::test]
+    async fn stops_receiving_while_tasks_wait_on_their_handler() {
+        let handler = "delivery = \"at-least-once\"\nmax_in_flight = 1";
+        let mq = dispatch_tasks(handler, "sleep 2", 10, |mq| mq.unacked() == 8).await;
+        assert_eq!(mq.waiting("task").len(), 2);
+    }
+}
@@ -1,21 +1,23 @@
-use futures::TryStreamExt;
-use pulsar::{
-    message::proto::command_subscribe::SubType, Consumer, Pulsar, TokioExecutor
-};
 use reqwest::Client;
 use anyhow::{bail, Result};
 
+mod callback;
 mod config;
 mod data;
 mod core;
+mod dispatch;
+mod mq;
 mod producer;
+mod schedule;
 mod serve;
 
-use data::DynamicTaskMessage;
-use core::{Forwarder, HandleResult};
+use callback::Callbacks;
+use core::Forwarder;
 use core::HandlerRepo;
+use dispatch::Dispatcher;
 
 use producer::Producer;
+use schedule::Scheduler;
 use serve::Serve;
 
 #[tokio::main]
@@ -28,60 +30,23 @@ async fn main() -> Result<()> {
         bail!("at least one topic must be specified in config `mq.topics`");
     }
 
-    let pulsar: Pulsar<_> = Pulsar::builder(config.mq.url, TokioExecutor).build().await?;
+    let mq = mq::connect(&config.mq).await?;
 
-    let submit_producer = Producer::<TokioExecutor>::new(&pulsar, "trampoline-dispatcher-submitter");
+    Scheduler::new(mq.clone(), &config.schedules)?.spawn();
 
-    let serve = Serve::new(submit_producer);
-    serve.spawn_start().await;
-
-    let mut consumer: Consumer<DynamicTaskMessage, _> = pulsar
-        .consumer()
-        .with_topics(config.mq.topics)
-        .with_consumer_name("trampoline-dispatcher")
-        .with_subscription_type(SubType::Exclusive)
-        .with_subscription("trampoline-dispatch")
-        .build()
-        .await?;
-
-    let mut producer = Producer::<TokioExecutor>::new(&pulsar, "trampoline-disapatcher-republish");
+    let submit_producer = Producer::new(mq.clone());
+    let callbacks = Callbacks::new(mq.clone());
 
     let client = Client::new();
-    let handlers = HandlerRepo::new(&config.handlers)?;
-    let processor = Forwarder::new(client, handlers);
+    let handlers = HandlerRepo::new(&config.handlers, config.dispatch.callback_url.as_deref(), &client, mq.clone())?;
 
-    let mut counter = 0usize;
-    while let Some(msg) = consumer.try_next().await? {
-        consumer.ack(&msg).await?;
-        let data = match msg.deserialize() {
-            Ok(data) => data,
-            Err(e) => {
-                log::error!("could not deserialize message: {:?}", e);
-                break;
-            }
-        };
-
-        let result = processor.process(&data).await?;
-        match result {
-            Some(HandleResult::Continue { status, response }) => {
-                for response_task in &response.tasks {
-                    producer.send(response_task).await?;
-                }
-                let message_id = format!("{}:{}:{}:{}", &msg.topic, msg.message_id.id.ledger_id, msg.message_id.id.entry_id, msg.message_id.id.partition.unwrap_or(-1));
-                let plural = if response.tasks.len() == 1 { "task" } else { "tasks" };
-                log::info!("messageId:<{}> task:<{}> status:<{}> result:<Continue:{} new {}>", message_id, &data.type_name, status, response.tasks.len(), plural);
-            },
-            Some(HandleResult::ContinueUnparseable { status, text }) => {
-                log::info!("got message {} {}, result: {} {}", &data.type_name, &data.task, status, text);
+    let serve = Serve::new(submit_producer, callbacks.clone(), handlers.breakers());
+    serve.spawn_start().await;
 
-            },
-            None => {
-                log::info!("could not find worker for {} {}", &data.type_name, &data.task)
-            }
-        };
+    let processor = Forwarder::new(client, handlers);
 
-        counter += 1;
-    }
+    let dispatcher = Dispatcher::new(mq, processor, callbacks, config.mq.dead_letter_topic, config.mq.expired_topic, config.dispatch.max_in_flight, config.dispatch.max_waiting);
+    let counter = dispatcher.run().await?;
     log::info!("got {} messages", counter);
     Ok(())
-}
\ No newline at end of file
+}
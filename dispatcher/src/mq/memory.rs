@@ -0,0 +1,253 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::sync::Notify;
+
+use super::{LocalValues, MessageQueue, Outgoing, Pauses, Received, NACK_DELAY};
+
+/// An in-process queue that doesn't survive restarts, for development and tests.
+/// Messages published to topics that aren't subscribed are kept but never received.
+pub struct MemoryQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    topics: Vec<String>,
+    state: Mutex<State>,
+    /// Woken whenever a message becomes available
+    available: Notify,
+    paused: Pauses,
+    values: LocalValues,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    queues: HashMap<String, VecDeque<Stored>>,
+    unacked: HashMap<String, Stored>,
+    /// Index into topics to receive from first, so that no topic starves the others
+    next_topic: usize,
+}
+
+struct Stored {
+    topic: String,
+    message_id: String,
+    payload: Vec<u8>,
+    properties: HashMap<String, String>,
+    redeliveries: u32,
+}
+
+impl MemoryQueue {
+    pub fn new(topics: &[String]) -> MemoryQueue {
+        let inner = Inner {
+            topics: topics.to_vec(),
+            state: Mutex::new(State::default()),
+            available: Notify::new(),
+            paused: Pauses::default(),
+            values: LocalValues::default(),
+        };
+        MemoryQueue { inner: Arc::new(inner) }
+    }
+
+    /// Payloads of the messages waiting on a topic
+    #[cfg(test)]
+    pub fn waiting(&self, topic: &str) -> Vec<Vec<u8>> {
+        let state = self.inner.state.lock().expect("memory queue lock poisoned");
+        state.queues.get(topic).map(|queue| queue.iter().map(|stored| stored.payload.clone()).collect()).unwrap_or_default()
+    }
+
+    /// Number of messages received but not yet acked or nacked
+    #[cfg(test)]
+    pub fn unacked(&self) -> usize {
+        self.inner.state.lock().expect("memory queue lock poisoned").unacked.len()
+    }
+}
+
+impl Inner {
+    fn push(&self, topic: &str, msg: Outgoing) {
+        let mut state = self.state.lock().expect("memory queue lock poisoned");
+        state.next_id += 1;
+        let stored = Stored {
+            topic: topic.to_owned(),
+            message_id: format!("{}:{}", topic, state.next_id),
+            payload: msg.payload,
+            properties: msg.properties,
+            redeliveries: 0,
+        };
+        drop(state);
+        self.enqueue(stored);
+    }
+
+    fn enqueue(&self, stored: Stored) {
+        let mut state = self.state.lock().expect("memory queue lock poisoned");
+        state.queues.entry(stored.topic.clone()).or_default().push_back(stored);
+        drop(state);
+        self.available.notify_one();
+    }
+
+    fn pop(&self) -> Option<Received> {
+        let active = self.paused.active(&self.topics);
+        let mut state = self.state.lock().expect("memory queue lock poisoned");
+        for i in 0..self.topics.len() {
+            let index = (state.next_topic + i) % self.topics.len();
+            if !active.contains(&self.topics[index]) {
+                continue;
+            }
+            let stored = state.queues.get_mut(&self.topics[index]).and_then(|queue| queue.pop_front());
+            if let Some(stored) = stored {
+                state.next_topic = (index + 1) % self.topics.len();
+                let received = Received {
+                    topic: stored.topic.clone(),
+                    message_id: stored.message_id.clone(),
+                    payload: stored.payload.clone(),
+                    properties: stored.properties.clone(),
+                    redeliveries: stored.redeliveries,
+                };
+                state.unacked.insert(stored.message_id.clone(), stored);
+                return Some(received);
+            }
+        }
+        None
+    }
+}
+
+#[async_trait]
+impl MessageQueue for MemoryQueue {
+    async fn receive(&self) -> Result<Option<Received>> {
+        loop {
+            if let Some(received) = self.inner.pop() {
+                return Ok(Some(received));
+            }
+            // notify_one stores a permit if nobody is waiting, so a push between pop and here isn't missed
+            self.inner.available.notified().await;
+        }
+    }
+
+    async fn ack(&self, msg: &Received) -> Result<()> {
+        let mut state = self.inner.state.lock().expect("memory queue lock poisoned");
+        state.unacked.remove(&msg.message_id)
+            .map(|_| ())
+            .ok_or_else(|| anyhow!("message {} is not pending", msg.message_id))
+    }
+
+    async fn nack(&self, msg: &Received) -> Result<()> {
+        let mut state = self.inner.state.lock().expect("memory queue lock poisoned");
+        let mut stored = state.unacked.remove(&msg.message_id)
+            .ok_or_else(|| anyhow!("message {} is not pending", msg.message_id))?;
+        stored.redeliveries += 1;
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(NACK_DELAY).await;
+            inner.enqueue(stored);
+        });
+        Ok(())
+    }
+
+    /// Redelivers the message itself with `next` as its payload, which carries the attempt
+    /// count in place of the redeliveries
+    async fn nack_at(&self, msg: &Received, next: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        let mut state = self.inner.state.lock().expect("memory queue lock poisoned");
+        let mut stored = state.unacked.remove(&msg.message_id)
+            .ok_or_else(|| anyhow!("message {} is not pending", msg.message_id))?;
+        stored.payload = next.payload;
+        stored.properties = next.properties;
+        stored.redeliveries = 0;
+        let delay = deliver_at.duration_since(SystemTime::now()).unwrap_or_default();
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            inner.enqueue(stored);
+        });
+        Ok(())
+    }
+
+    fn pause(&self, topic: &str, until: SystemTime) {
+        self.inner.paused.pause(topic, until);
+        // Wakes the receiver once the topic's messages can be delivered again
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(until.duration_since(SystemTime::now()).unwrap_or_default()).await;
+            inner.available.notify_one();
+        });
+    }
+
+    async fn publish(&self, topic: &str, msg: Outgoing) -> Result<()> {
+        self.inner.push(topic, msg);
+        Ok(())
+    }
+
+    async fn publish_at(&self, topic: &str, msg: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        let delay = deliver_at.duration_since(SystemTime::now()).unwrap_or_default();
+        let inner = self.inner.clone();
+        let topic = topic.to_owned();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            inner.push(&topic, msg);
+        });
+        Ok(())
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.inner.values.get(key))
+    }
+
+    async fn compare_and_set(&self, key: &str, expected: Option<&str>, value: &str) -> Result<bool> {
+        Ok(self.inner.values.compare_and_set(key, expected, value))
+    }
+
+    async fn delete_value(&self, key: &str) -> Result<()> {
+        self.inner.values.delete(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    use crate::mq::{MessageQueue, Outgoing};
+
+    use super::MemoryQueue;
+
+    fn outgoing(payload: &str) -> Outgoing {
+        Outgoing { payload: payload.as_bytes().to_vec(), ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn paused_topic_is_delivered_once_the_pause_ends() {
+        let queue = MemoryQueue::new(&["paused".to_owned(), "other".to_owned()]);
+        let paused_until = SystemTime::now() + Duration::from_millis(500);
+        queue.pause("paused", paused_until);
+        queue.publish("paused", outgoing("later")).await.unwrap();
+        queue.publish("other", outgoing("now")).await.unwrap();
+
+        let msg = queue.receive().await.unwrap().unwrap();
+        assert_eq!(msg.payload, b"now");
+        let msg = tokio::time::timeout(Duration::from_secs(5), queue.receive()).await.unwrap().unwrap().unwrap();
+        assert!(SystemTime::now() >= paused_until);
+        assert_eq!(msg.payload, b"later");
+    }
+
+    #[tokio::test]
+    async fn messages_keep_their_properties() {
+        let queue = MemoryQueue::new(&["task".to_owned()]);
+        let with_origin = |payload: &str, origin: &str| Outgoing {
+            properties: HashMap::from([("origin".to_owned(), origin.to_owned())]),
+            ..outgoing(payload)
+        };
+        queue.publish("task", with_origin("now", "publish")).await.unwrap();
+        queue.publish_at("task", with_origin("later", "publish_at"), SystemTime::now() + Duration::from_millis(200)).await.unwrap();
+
+        let msg = queue.receive().await.unwrap().unwrap();
+        assert_eq!(msg.properties["origin"], "publish");
+        queue.nack_at(&msg, with_origin("next", "nack_at"), SystemTime::now() + Duration::from_millis(400)).await.unwrap();
+        let msg = queue.receive().await.unwrap().unwrap();
+        assert_eq!((msg.payload.as_slice(), msg.properties["origin"].as_str()), (b"later".as_slice(), "publish_at"));
+        let msg = queue.receive().await.unwrap().unwrap();
+        assert_eq!((msg.payload.as_slice(), msg.properties["origin"].as_str()), (b"next".as_slice(), "nack_at"));
+    }
+}
@@ -0,0 +1,175 @@
+mod memory;
+mod nats;
+mod postgres;
+mod pulsar;
+mod redis;
+mod sqlite;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+
+use crate::config::{Backend, Mq};
+
+pub use self::memory::MemoryQueue;
+pub use self::nats::NatsQueue;
+pub use self::postgres::PostgresQueue;
+pub use self::pulsar::PulsarQueue;
+pub use self::redis::RedisQueue;
+pub use self::sqlite::SqliteQueue;
+
+/// How long a nacked message waits before it's redelivered, on queues that redeliver it
+/// themselves, so that a task failing right away isn't retried in a tight loop
+pub const NACK_DELAY: Duration = Duration::from_secs(1);
+
+/// A message received from one of the subscribed topics
+pub struct Received {
+    pub topic: String,
+    /// Identifies the message within the queue, for acking and logging
+    pub message_id: String,
+    pub payload: Vec<u8>,
+    /// Properties it was published with, such as why a dead-lettered message was given up on
+    pub properties: HashMap<String, String>,
+    /// Times this message was delivered before, such as after a nack or a consumer crashing.
+    /// On queues whose `nack_at` puts the attempt count in the payload, they start again from 0.
+    pub redeliveries: u32,
+}
+
+/// A message to publish
+#[derive(Default)]
+pub struct Outgoing {
+    pub payload: Vec<u8>,
+    pub properties: HashMap<String, String>,
+}
+
+/// The message queue the Dispatcher consumes tasks from and publishes tasks to
+#[async_trait]
+pub trait MessageQueue: Send + Sync {
+    /// Waits for the next message on any subscribed topic. Returns None once the queue is closed.
+    async fn receive(&self) -> Result<Option<Received>>;
+
+    /// Marks a received message as processed, so it's never redelivered
+    async fn ack(&self, msg: &Received) -> Result<()>;
+
+    /// Marks a received message as failed, so it's redelivered after about `NACK_DELAY`
+    async fn nack(&self, msg: &Received) -> Result<()>;
+
+    /// Marks a received message as failed, so it's redelivered no earlier than `deliver_at`.
+    /// By default this publishes `next`, the message for the next attempt, for later delivery
+    /// and acks the original, giving it a new message ID. Queues that can should redeliver the
+    /// original instead.
+    async fn nack_at(&self, msg: &Received, next: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        self.publish_at(&msg.topic, next, deliver_at).await?;
+        self.ack(msg).await
+    }
+
+    /// Marks a received message as one that can never be processed, so it's not redelivered.
+    /// By default this is the same as acking it.
+    async fn term(&self, msg: &Received) -> Result<()> {
+        self.ack(msg).await
+    }
+
+    /// Stops delivering messages of `topic` until `until`, such as while its handler's worker
+    /// is down. By default the queue goes on delivering them, and they wait in the Dispatcher.
+    fn pause(&self, _topic: &str, _until: SystemTime) {}
+
+    /// Publishes a message, returning once the queue has confirmed it
+    async fn publish(&self, topic: &str, msg: Outgoing) -> Result<()>;
+
+    /// Publishes a message that's delivered no earlier than `deliver_at`
+    async fn publish_at(&self, topic: &str, msg: Outgoing, deliver_at: SystemTime) -> Result<()>;
+
+    /// Whether the queue's values are shared by all Dispatchers using it, rather than kept by
+    /// each Dispatcher for itself. Features that coordinate Dispatchers through shared values
+    /// are then only correct with a single Dispatcher.
+    fn shares_values(&self) -> bool {
+        true
+    }
+
+    /// Reads a value shared by all Dispatchers using the queue
+    async fn get_value(&self, key: &str) -> Result<Option<String>>;
+
+    /// Sets a shared value, but only if it's currently `expected`, where None means unset.
+    /// Returns whether it was set. This lets Dispatchers coordinate, e.g. so that only one
+    /// of them runs each tick of a schedule.
+    async fn compare_and_set(&self, key: &str, expected: Option<&str>, value: &str) -> Result<bool>;
+
+    /// Unsets a shared value
+    async fn delete_value(&self, key: &str) -> Result<()>;
+}
+
+/// Topics that a queue has stopped delivering, each until a given time
+#[derive(Default)]
+struct Pauses(Mutex<HashMap<String, SystemTime>>);
+
+impl Pauses {
+    /// Pauses `topic` until `until`, or until an earlier time it's already paused until, since
+    /// tasks held back together are each given their own time to try again
+    fn pause(&self, topic: &str, until: SystemTime) {
+        let mut paused = self.0.lock().unwrap();
+        let until = match paused.get(topic) {
+            Some(paused_until) if *paused_until > SystemTime::now() => until.min(*paused_until),
+            _ => until,
+        };
+        paused.insert(topic.to_owned(), until);
+    }
+
+    /// The topics among `topics` that aren't paused
+    fn active(&self, topics: &[String]) -> Vec<String> {
+        let now = SystemTime::now();
+        let mut paused = self.0.lock().unwrap();
+        paused.retain(|_, until| *until > now);
+        topics.iter().filter(|topic| !paused.contains_key(*topic)).cloned().collect()
+    }
+}
+
+/// Values kept in the Dispatcher's memory, for queues that have nowhere to keep them
+#[derive(Default)]
+struct LocalValues(Mutex<HashMap<String, String>>);
+
+impl LocalValues {
+    fn get(&self, key: &str) -> Option<String> {
+        self.0.lock().unwrap().get(key).cloned()
+    }
+
+    fn compare_and_set(&self, key: &str, expected: Option<&str>, value: &str) -> bool {
+        let mut values = self.0.lock().unwrap();
+        if values.get(key).map(String::as_str) != expected {
+            return false;
+        }
+        values.insert(key.to_owned(), value.to_owned());
+        true
+    }
+
+    fn delete(&self, key: &str) {
+        self.0.lock().unwrap().remove(key);
+    }
+}
+
+/// Connects to the backend selected in config, subscribed to its topics
+pub async fn connect(config: &Mq) -> Result<Arc<dyn MessageQueue>> {
+    let mq: Arc<dyn MessageQueue> = match config.backend {
+        Backend::Pulsar => {
+            let url = config.url.as_deref().context("config `mq.url` is required for the pulsar backend")?;
+            Arc::new(PulsarQueue::connect(url, &config.topics).await?)
+        },
+        Backend::Memory => Arc::new(MemoryQueue::new(&config.topics)),
+        Backend::Nats => {
+            let url = config.url.as_deref().context("config `mq.url` is required for the nats backend")?;
+            Arc::new(NatsQueue::connect(url, &config.topics, &config.nats).await?)
+        },
+        Backend::Sqlite => Arc::new(SqliteQueue::open(&config.topics, &config.sqlite)?),
+        Backend::Redis => {
+            let url = config.url.as_deref().context("config `mq.url` is required for the redis backend")?;
+            Arc::new(RedisQueue::connect(url, &config.topics, &config.redis).await?)
+        },
+        Backend::Postgres => {
+            let url = config.url.as_deref().context("config `mq.url` is required for the postgres backend")?;
+            Arc::new(PostgresQueue::connect(url, &config.topics, &config.postgres).await?)
+        },
+    };
+    Ok(mq)
+}
@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use async_nats::jetstream::kv::{self, CreateErrorKind, UpdateErrorKind};
+use async_nats::jetstream::{self, consumer, stream, AckKind};
+use async_nats::HeaderMap;
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::sync::Mutex;
+
+use crate::config::Nats;
+
+use super::{MessageQueue, Outgoing, Received, NACK_DELAY};
+
+/// Header on a delayed message holding when it's due, in milliseconds since the epoch
+const DELIVER_AT_HEADER: &str = "Trampoline-Deliver-At";
+
+/// Messages pulled at a time. Kept small because pulled messages wait in the buffer while
+/// counting towards the ack wait.
+const READ_BATCH: usize = 10;
+
+/// NATS JetStream, with one subject per topic read through a durable pull consumer.
+///
+/// JetStream can't publish a message for later delivery, so delayed messages go to a separate
+/// `{subject_prefix}-delayed.{topic}` subject, where a second consumer naks them with a delay
+/// until they're due and then moves them onto their topic's subject.
+pub struct NatsQueue {
+    jetstream: jetstream::Context,
+    subject_prefix: String,
+    messages: Mutex<consumer::pull::Stream>,
+    /// Received messages that haven't been settled yet, by `pending_key`
+    pending: Arc<std::sync::Mutex<HashMap<String, jetstream::Message>>>,
+    /// Bucket holding shared values
+    values: kv::Store,
+}
+
+impl NatsQueue {
+    pub async fn connect(url: &str, topics: &[String], options: &Nats) -> Result<NatsQueue> {
+        let client = async_nats::connect(url).await.context("connecting to nats failed")?;
+        let jetstream = jetstream::new(client);
+        let prefix = &options.subject_prefix;
+
+        // Work queue retention deletes each message once it's acked
+        jetstream.get_or_create_stream(stream::Config {
+            name: options.stream.clone(),
+            subjects: vec![format!("{}.>", prefix), format!("{}-delayed.>", prefix)],
+            retention: stream::RetentionPolicy::WorkQueue,
+            ..Default::default()
+        }).await.context(format!("creating stream `{}` failed", options.stream))?;
+
+        let ack_wait = Duration::from_millis(options.ack_wait_ms);
+        let consumer: consumer::PullConsumer = jetstream.create_consumer_on_stream(consumer::pull::Config {
+            durable_name: Some(options.durable.clone()),
+            filter_subjects: topics.iter().map(|topic| format!("{}.{}", prefix, topic)).collect(),
+            ack_policy: consumer::AckPolicy::Explicit,
+            ack_wait,
+            ..Default::default()
+        }, &options.stream).await.context(format!("creating consumer `{}` failed", options.durable))?;
+        let delayed: consumer::PullConsumer = jetstream.create_consumer_on_stream(consumer::pull::Config {
+            durable_name: Some(format!("{}-delayed", options.durable)),
+            filter_subject: format!("{}-delayed.>", prefix),
+            ack_policy: consumer::AckPolicy::Explicit,
+            ack_wait,
+            ..Default::default()
+        }, &options.stream).await.context(format!("creating consumer `{}-delayed` failed", options.durable))?;
+
+        let bucket = format!("{}-values", options.stream);
+        let values = match jetstream.get_key_value(&bucket).await {
+            Ok(values) => values,
+            Err(_) => jetstream.create_key_value(kv::Config { bucket: bucket.clone(), ..Default::default() })
+                .await.context(format!("creating key-value bucket `{}` failed", bucket))?,
+        };
+
+        let messages = consumer.stream().max_messages_per_batch(READ_BATCH).messages().await?;
+        tokio::spawn(Self::deliver_delayed(jetstream.clone(), delayed, prefix.clone()));
+        let pending = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        tokio::spawn(Self::keep_in_progress(Arc::downgrade(&pending), ack_wait / 2));
+        Ok(NatsQueue {
+            jetstream,
+            subject_prefix: prefix.clone(),
+            messages: Mutex::new(messages),
+            pending,
+            values,
+        })
+    }
+
+    /// Tells JetStream that the pending messages are still being worked on each interval, which
+    /// restarts their ack wait, for as long as the queue is alive. Otherwise a task that takes
+    /// longer than the ack wait would be redelivered and run twice.
+    async fn keep_in_progress(pending: Weak<std::sync::Mutex<HashMap<String, jetstream::Message>>>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(pending) = pending.upgrade() else {
+                return;
+            };
+            let messages: Vec<_> = pending.lock().unwrap().values().cloned().collect();
+            drop(pending);
+            for msg in messages {
+                if let Err(e) = msg.ack_with(AckKind::Progress).await {
+                    log::error!("marking message {} in progress failed: {:?}", msg.subject, e);
+                }
+            }
+        }
+    }
+
+    /// Moves delayed messages onto their topic's subject once they're due, naking them with
+    /// the time left until then
+    async fn deliver_delayed(jetstream: jetstream::Context, consumer: consumer::PullConsumer, prefix: String) {
+        let delayed_prefix = format!("{}-delayed.", prefix);
+        loop {
+            let mut messages = match consumer.stream().max_messages_per_batch(READ_BATCH).messages().await {
+                Ok(messages) => messages,
+                Err(e) => {
+                    log::error!("reading delayed messages failed: {:?}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            while let Some(msg) = messages.next().await {
+                let result = match msg {
+                    Ok(msg) => Self::deliver_if_due(&jetstream, &msg, &prefix, &delayed_prefix).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    log::error!("delivering delayed message failed: {:?}", e);
+                }
+            }
+        }
+    }
+
+    async fn deliver_if_due(jetstream: &jetstream::Context, msg: &jetstream::Message, prefix: &str, delayed_prefix: &str) -> Result<()> {
+        let headers = msg.headers.clone().unwrap_or_default();
+        let deliver_at = headers.get(DELIVER_AT_HEADER)
+            .and_then(|value| value.as_str().parse::<u64>().ok())
+            .map(|ms| UNIX_EPOCH + Duration::from_millis(ms))
+            .unwrap_or(UNIX_EPOCH);
+        if let Ok(remaining) = deliver_at.duration_since(SystemTime::now()) {
+            return msg.ack_with(AckKind::Nak(Some(remaining))).await.map_err(anyhow::Error::msg);
+        }
+        let topic = msg.subject.as_str().strip_prefix(delayed_prefix).unwrap_or(msg.subject.as_str());
+        let mut undelayed = HeaderMap::new();
+        for (name, values) in headers.iter().filter(|(name, _)| AsRef::<str>::as_ref(name) != DELIVER_AT_HEADER) {
+            for value in values {
+                undelayed.append(name.clone(), value.clone());
+            }
+        }
+        jetstream.publish_with_headers(format!("{}.{}", prefix, topic), undelayed, msg.payload.clone()).await?.await?;
+        msg.ack().await.map_err(anyhow::Error::msg)
+    }
+
+    fn subject(&self, topic: &str) -> String {
+        format!("{}.{}", self.subject_prefix, topic)
+    }
+
+    fn headers(properties: HashMap<String, String>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in properties {
+            headers.insert(name, value);
+        }
+        headers
+    }
+
+    /// The properties a message was published with, from its headers
+    fn properties(headers: &HeaderMap) -> HashMap<String, String> {
+        headers.iter()
+            .filter_map(|(name, values)| Some((name.to_string(), values.first()?.to_string())))
+            .collect()
+    }
+
+    /// The key of a received message among the pending ones. A message redelivered while it's
+    /// still pending, such as after a stall, is delivered again with the same ID, so each
+    /// delivery has its own key.
+    fn pending_key(msg: &Received) -> String {
+        format!("{}#{}", msg.message_id, msg.redeliveries)
+    }
+
+    async fn settle(&self, msg: &Received, kind: AckKind) -> Result<()> {
+        let pending = self.pending.lock().unwrap().remove(&Self::pending_key(msg));
+        let pending = pending.context(format!("message {} is not pending", msg.message_id))?;
+        pending.ack_with(kind).await.map_err(anyhow::Error::msg)
+    }
+}
+
+#[async_trait]
+impl MessageQueue for NatsQueue {
+    async fn receive(&self) -> Result<Option<Received>> {
+        let mut messages = self.messages.lock().await;
+        let prefix = format!("{}.", self.subject_prefix);
+        loop {
+            let msg = match messages.next().await {
+                Some(Ok(msg)) => msg,
+                // The stream reconnects by itself, so errors such as missed heartbeats aren't fatal
+                Some(Err(e)) => {
+                    log::warn!("receiving from nats failed: {:?}", e);
+                    continue;
+                },
+                None => return Ok(None),
+            };
+            let info = msg.info().map_err(anyhow::Error::msg)?;
+            let topic = msg.subject.as_str().strip_prefix(&prefix).unwrap_or(msg.subject.as_str()).to_owned();
+            let received = Received {
+                message_id: format!("{}:{}", topic, info.stream_sequence),
+                redeliveries: (info.delivered - 1).max(0) as u32,
+                topic,
+                payload: msg.payload.to_vec(),
+                properties: msg.headers.as_ref().map(Self::properties).unwrap_or_default(),
+            };
+            self.pending.lock().unwrap().insert(Self::pending_key(&received), msg);
+            return Ok(Some(received));
+        }
+    }
+
+    async fn ack(&self, msg: &Received) -> Result<()> {
+        self.settle(msg, AckKind::Ack).await
+    }
+
+    async fn nack(&self, msg: &Received) -> Result<()> {
+        self.settle(msg, AckKind::Nak(Some(NACK_DELAY))).await
+    }
+
+    /// Naks the message with a delay, so JetStream redelivers the original. Its redelivery
+    /// count makes up for the attempt count in `next` not being published.
+    async fn nack_at(&self, msg: &Received, _next: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        let delay = deliver_at.duration_since(SystemTime::now()).unwrap_or_default();
+        self.settle(msg, AckKind::Nak(Some(delay))).await
+    }
+
+    async fn term(&self, msg: &Received) -> Result<()> {
+        self.settle(msg, AckKind::Term).await
+    }
+
+    async fn publish(&self, topic: &str, msg: Outgoing) -> Result<()> {
+        let headers = Self::headers(msg.properties);
+        self.jetstream.publish_with_headers(self.subject(topic), headers, msg.payload.into()).await?.await?;
+        Ok(())
+    }
+
+    async fn publish_at(&self, topic: &str, msg: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        let mut headers = Self::headers(msg.properties);
+        headers.insert(DELIVER_AT_HEADER, deliver_at.duration_since(UNIX_EPOCH)?.as_millis().to_string());
+        let subject = format!("{}-delayed.{}", self.subject_prefix, topic);
+        self.jetstream.publish_with_headers(subject, headers, msg.payload.into()).await?.await?;
+        Ok(())
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<String>> {
+        let value = self.values.get(key).await?;
+        Ok(value.map(|value| String::from_utf8_lossy(&value).into_owned()))
+    }
+
+    /// Uses the revision of the key's entry, so the value is only set if nobody set it in between
+    async fn compare_and_set(&self, key: &str, expected: Option<&str>, value: &str) -> Result<bool> {
+        let value = value.to_owned().into();
+        let Some(expected) = expected else {
+            return match self.values.create(key, value).await {
+                Ok(_) => Ok(true),
+                Err(e) if e.kind() == CreateErrorKind::AlreadyExists => Ok(false),
+                Err(e) => Err(e.into()),
+            };
+        };
+        let entry = match self.values.entry(key).await? {
+            Some(entry) if entry.operation == kv::Operation::Put && entry.value == expected.as_bytes() => entry,
+            _ => return Ok(false),
+        };
+        match self.values.update(key, value, entry.revision).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == UpdateErrorKind::WrongLastRevision => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete_value(&self, key: &str) -> Result<()> {
+        self.values.delete(key).await?;
+        Ok(())
+    }
+}
+
+/// These need a NATS server with JetStream enabled, so they're ignored unless run with
+/// `--ignored` and `NATS_URL` set, e.g. `NATS_URL=nats://localhost cargo test -- --ignored nats`
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    use crate::config::Nats;
+    use crate::mq::{MessageQueue, Outgoing, Received, NACK_DELAY};
+
+    use super::NatsQueue;
+
+    const TOPIC: &str = "task";
+
+    /// A queue on its own stream, so that tests don't see each other's messages
+    async fn connect() -> (NatsQueue, Nats) {
+        connect_with_ack_wait(Nats::default().ack_wait_ms).await
+    }
+
+    async fn connect_with_ack_wait(ack_wait_ms: u64) -> (NatsQueue, Nats) {
+        let url = std::env::var("NATS_URL").expect("NATS_URL must be set to run the NATS tests");
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let options = Nats {
+            subject_prefix: format!("trampoline-test-{}", id),
+            stream: format!("TRAMPOLINE_TEST_{}", id),
+            ack_wait_ms,
+            ..Default::default()
+        };
+        let queue = NatsQueue::connect(&url, &[TOPIC.to_owned()], &options).await.unwrap();
+        (queue, options)
+    }
+
+    async fn delete(queue: NatsQueue, options: &Nats) {
+        queue.jetstream.delete_key_value(format!("{}-values", options.stream)).await.unwrap();
+        queue.jetstream.delete_stream(&options.stream).await.unwrap();
+    }
+
+    fn outgoing(payload: &str) -> Outgoing {
+        Outgoing {
+            payload: payload.as_bytes().to_vec(),
+            properties: HashMap::from([("origin".to_owned(), "test".to_owned())]),
+        }
+    }
+
+    async fn receive(queue: &NatsQueue) -> Received {
+        tokio::time::timeout(Duration::from_secs(10), queue.receive()).await
+            .expect("no message received")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn acked_message_is_not_redelivered() {
+        let (queue, options) = connect().await;
+        queue.publish(TOPIC, outgoing("first")).await.unwrap();
+        queue.publish(TOPIC, outgoing("second")).await.unwrap();
+        let msg = receive(&queue).await;
+        assert_eq!(msg.topic, TOPIC);
+        assert_eq!(msg.payload, b"first");
+        assert_eq!(msg.redeliveries, 0);
+        queue.ack(&msg).await.unwrap();
+        let msg = receive(&queue).await;
+        assert_eq!(msg.payload, b"second");
+        queue.ack(&msg).await.unwrap();
+        delete(queue, &options).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn nacked_message_is_redelivered_after_the_nack_delay() {
+        let (queue, options) = connect().await;
+        queue.publish(TOPIC, outgoing("task")).await.unwrap();
+        let msg = receive(&queue).await;
+        let nacked_at = SystemTime::now();
+        queue.nack(&msg).await.unwrap();
+
+        let redelivered = receive(&queue).await;
+        assert!(SystemTime::now() >= nacked_at + NACK_DELAY);
+        assert_eq!(redelivered.message_id, msg.message_id);
+        assert_eq!(redelivered.payload, b"task");
+        assert_eq!(redelivered.redeliveries, 1);
+        queue.ack(&redelivered).await.unwrap();
+        delete(queue, &options).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn nack_at_redelivers_the_original_when_due() {
+        let (queue, options) = connect().await;
+        queue.publish(TOPIC, outgoing("attempt 1")).await.unwrap();
+        let msg = receive(&queue).await;
+        let deliver_at = SystemTime::now() + Duration::from_secs(2);
+        queue.nack_at(&msg, outgoing("attempt 2"), deliver_at).await.unwrap();
+
+        let redelivered = receive(&queue).await;
+        assert!(SystemTime::now() >= deliver_at);
+        assert_eq!(redelivered.message_id, msg.message_id);
+        // JetStream's redelivery count stands in for the attempt count in `next`
+        assert_eq!(redelivered.payload, b"attempt 1");
+        assert_eq!(redelivered.redeliveries, 1);
+        queue.ack(&redelivered).await.unwrap();
+        delete(queue, &options).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn message_published_for_later_is_delivered_when_due() {
+        let (queue, options) = connect().await;
+        let deliver_at = SystemTime::now() + Duration::from_secs(2);
+        queue.publish_at(TOPIC, outgoing("later"), deliver_at).await.unwrap();
+
+        let msg = receive(&queue).await;
+        assert!(SystemTime::now() >= deliver_at);
+        assert_eq!(msg.topic, TOPIC);
+        assert_eq!(msg.payload, b"later");
+        assert_eq!(msg.redeliveries, 0);
+        queue.ack(&msg).await.unwrap();
+        delete(queue, &options).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn message_handled_for_longer_than_the_ack_wait_isnt_redelivered() {
+        let (queue, options) = connect_with_ack_wait(1000).await;
+        queue.publish(TOPIC, outgoing("slow task")).await.unwrap();
+        let msg = receive(&queue).await;
+
+        let redelivered = tokio::time::timeout(Duration::from_secs(3), queue.receive()).await;
+        assert!(redelivered.is_err(), "the message was redelivered while it was being handled");
+        queue.ack(&msg).await.unwrap();
+        delete(queue, &options).await;
+    }
+}
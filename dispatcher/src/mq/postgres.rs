@@ -0,0 +1,433 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use tokio::sync::{Mutex, RwLock};
+use tokio_postgres::{Client, NoTls};
+
+use crate::config::Postgres;
+
+use super::{MessageQueue, Outgoing, Pauses, Received, NACK_DELAY};
+
+/// A table of messages in PostgreSQL, so that applications can enqueue tasks in the same
+/// transaction as their own writes. Messages are claimed with `FOR UPDATE SKIP LOCKED`, hidden
+/// for a visibility timeout while they're processed, and deleted when acked.
+pub struct PostgresQueue {
+    conn: Arc<Connection>,
+    topics: Vec<String>,
+    /// Rows claimed by this Dispatcher and not yet settled, which are hidden for another
+    /// visibility timeout every so often. It's locked while they are, so that a row settled
+    /// meanwhile isn't hidden again.
+    leased: Arc<Mutex<HashSet<i64>>>,
+    paused: Pauses,
+    visibility_timeout: Duration,
+    poll_interval: Duration,
+    claim_sql: String,
+    ack_sql: String,
+    nack_sql: String,
+    nack_at_sql: String,
+    publish_sql: String,
+    publish_at_sql: String,
+    get_value_sql: String,
+    insert_value_sql: String,
+    update_value_sql: String,
+    delete_value_sql: String,
+}
+
+/// A client that connects again once its connection is lost, e.g. when the database restarts
+struct Connection {
+    url: String,
+    client: RwLock<Arc<Client>>,
+}
+
+impl Connection {
+    async fn open(url: &str) -> Result<Connection> {
+        let client = Self::connect(url).await?;
+        Ok(Connection { url: url.to_owned(), client: RwLock::new(Arc::new(client)) })
+    }
+
+    async fn connect(url: &str) -> Result<Client> {
+        let (client, connection) = tokio_postgres::connect(url, NoTls).await
+            .context("connecting to postgres failed")?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::error!("postgres connection failed: {:?}", e);
+            }
+        });
+        Ok(client)
+    }
+
+    /// The client, connected again first if its connection was lost
+    async fn client(&self) -> Result<Arc<Client>> {
+        let client = self.client.read().await.clone();
+        if !client.is_closed() {
+            return Ok(client);
+        }
+        let mut client = self.client.write().await;
+        if client.is_closed() {
+            log::warn!("postgres connection lost, connecting again");
+            *client = Arc::new(Self::connect(&self.url).await?);
+        }
+        Ok(client.clone())
+    }
+}
+
+impl PostgresQueue {
+    pub async fn connect(url: &str, topics: &[String], options: &Postgres) -> Result<PostgresQueue> {
+        let conn = Arc::new(Connection::open(url).await?);
+        let table = &options.table;
+        conn.client().await?.batch_execute(&format!(r#"
+            CREATE TABLE IF NOT EXISTS "{table}" (
+                id BIGSERIAL PRIMARY KEY,
+                topic TEXT NOT NULL,
+                payload BYTEA NOT NULL,
+                properties JSONB NOT NULL DEFAULT '{{}}',
+                visible_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                deliveries INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE INDEX IF NOT EXISTS "{table}_topic_visible_at" ON "{table}" (topic, visible_at);
+            CREATE TABLE IF NOT EXISTS "{table}_values" (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+        "#)).await.context(format!("creating table `{}` failed", table))?;
+
+        let visibility_timeout = Duration::from_millis(options.visibility_timeout_ms);
+        let leased = Arc::new(Mutex::new(HashSet::new()));
+        let extend_sql = format!(r#"UPDATE "{table}" SET visible_at = now() + $2::float8 * interval '1 millisecond' WHERE id = ANY($1)"#);
+        tokio::spawn(Self::keep_leased(conn.clone(), Arc::downgrade(&leased), extend_sql, visibility_timeout));
+        Ok(PostgresQueue {
+            conn,
+            topics: topics.to_vec(),
+            leased,
+            paused: Pauses::default(),
+            visibility_timeout,
+            poll_interval: Duration::from_millis(options.poll_interval_ms),
+            claim_sql: format!(r#"
+                UPDATE "{table}"
+                SET visible_at = now() + $2::float8 * interval '1 millisecond', deliveries = deliveries + 1
+                WHERE id = (
+                    SELECT id FROM "{table}"
+                    WHERE topic = ANY($1) AND visible_at <= now()
+                    ORDER BY visible_at, id
+                    FOR UPDATE SKIP LOCKED
+                    LIMIT 1
+                )
+                RETURNING id, topic, payload, properties, deliveries"#),
+            ack_sql: format!(r#"DELETE FROM "{table}" WHERE id = $1"#),
+            nack_sql: format!(r#"UPDATE "{table}" SET visible_at = now() + $2::float8 * interval '1 millisecond' WHERE id = $1"#),
+            nack_at_sql: format!(r#"UPDATE "{table}" SET payload = $2, properties = $4, visible_at = $3, deliveries = 0 WHERE id = $1"#),
+            publish_sql: format!(r#"INSERT INTO "{table}" (topic, payload, properties) VALUES ($1, $2, $3)"#),
+            publish_at_sql: format!(r#"INSERT INTO "{table}" (topic, payload, properties, visible_at) VALUES ($1, $2, $3, $4)"#),
+            get_value_sql: format!(r#"SELECT value FROM "{table}_values" WHERE key = $1"#),
+            insert_value_sql: format!(r#"INSERT INTO "{table}_values" (key, value) VALUES ($1, $2) ON CONFLICT DO NOTHING"#),
+            update_value_sql: format!(r#"UPDATE "{table}_values" SET value = $2 WHERE key = $1 AND value = $3"#),
+            delete_value_sql: format!(r#"DELETE FROM "{table}_values" WHERE key = $1"#),
+        })
+    }
+
+    /// Hides the rows this Dispatcher has claimed for another visibility timeout every half of
+    /// one, for as long as the queue is alive. Otherwise a task that takes longer than the
+    /// timeout would be claimed again and run twice.
+    async fn keep_leased(conn: Arc<Connection>, leased: Weak<Mutex<HashSet<i64>>>, extend_sql: String, visibility_timeout: Duration) {
+        let timeout_ms = visibility_timeout.as_millis() as f64;
+        loop {
+            tokio::time::sleep(visibility_timeout / 2).await;
+            let Some(leased) = leased.upgrade() else {
+                return;
+            };
+            let leased = leased.lock().await;
+            if leased.is_empty() {
+                continue;
+            }
+            let ids: Vec<i64> = leased.iter().copied().collect();
+            let extended = match conn.client().await {
+                Ok(client) => client.execute(&extend_sql, &[&ids, &timeout_ms]).await.map_err(anyhow::Error::from),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = extended {
+                log::error!("extending the visibility timeout of claimed messages failed: {:?}", e);
+            }
+        }
+    }
+
+    /// Stops hiding a settled row, returning its ID
+    async fn release(&self, msg: &Received) -> Result<i64> {
+        let id = Self::row_id(msg)?;
+        self.leased.lock().await.remove(&id);
+        Ok(id)
+    }
+
+    /// The row ID of a received message
+    fn row_id(msg: &Received) -> Result<i64> {
+        let id = msg.message_id.rsplit_once(':').map(|(_, id)| id).unwrap_or(&msg.message_id);
+        id.parse().context(format!("invalid message id `{}`", msg.message_id))
+    }
+
+    fn properties(properties: HashMap<String, String>) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(properties)?)
+    }
+}
+
+#[async_trait]
+impl MessageQueue for PostgresQueue {
+    async fn receive(&self) -> Result<Option<Received>> {
+        let timeout_ms = self.visibility_timeout.as_millis() as f64;
+        loop {
+            let row = self.conn.client().await?.query_opt(&self.claim_sql, &[&self.paused.active(&self.topics), &timeout_ms]).await?;
+            if let Some(row) = row {
+                let id: i64 = row.get("id");
+                self.leased.lock().await.insert(id);
+                let topic: String = row.get("topic");
+                let deliveries: i32 = row.get("deliveries");
+                let properties: serde_json::Value = row.get("properties");
+                return Ok(Some(Received {
+                    message_id: format!("{}:{}", topic, id),
+                    topic,
+                    payload: row.get("payload"),
+                    properties: serde_json::from_value(properties)?,
+                    redeliveries: (deliveries - 1).max(0) as u32,
+                }));
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    async fn ack(&self, msg: &Received) -> Result<()> {
+        let id = self.release(msg).await?;
+        self.conn.client().await?.execute(&self.ack_sql, &[&id]).await?;
+        Ok(())
+    }
+
+    async fn nack(&self, msg: &Received) -> Result<()> {
+        let delay_ms = NACK_DELAY.as_millis() as f64;
+        let id = self.release(msg).await?;
+        self.conn.client().await?.execute(&self.nack_sql, &[&id, &delay_ms]).await?;
+        Ok(())
+    }
+
+    /// Redelivers the row itself with `next` as its payload, which carries the attempt count
+    /// in place of the deliveries, so they start again from 0
+    async fn nack_at(&self, msg: &Received, next: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        let id = self.release(msg).await?;
+        let properties = Self::properties(next.properties)?;
+        self.conn.client().await?.execute(&self.nack_at_sql, &[&id, &next.payload, &deliver_at, &properties]).await?;
+        Ok(())
+    }
+
+    fn pause(&self, topic: &str, until: SystemTime) {
+        self.paused.pause(topic, until);
+    }
+
+    async fn publish(&self, topic: &str, msg: Outgoing) -> Result<()> {
+        let properties = Self::properties(msg.properties)?;
+        self.conn.client().await?.execute(&self.publish_sql, &[&topic, &msg.payload, &properties]).await?;
+        Ok(())
+    }
+
+    async fn publish_at(&self, topic: &str, msg: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        let properties = Self::properties(msg.properties)?;
+        self.conn.client().await?.execute(&self.publish_at_sql, &[&topic, &msg.payload, &properties, &deliver_at]).await?;
+        Ok(())
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<String>> {
+        let row = self.conn.client().await?.query_opt(&self.get_value_sql, &[&key]).await?;
+        Ok(row.map(|row| row.get("value")))
+    }
+
+    async fn compare_and_set(&self, key: &str, expected: Option<&str>, value: &str) -> Result<bool> {
+        let updated = match expected {
+            None => self.conn.client().await?.execute(&self.insert_value_sql, &[&key, &value]).await?,
+            Some(expected) => self.conn.client().await?.execute(&self.update_value_sql, &[&key, &value, &expected]).await?,
+        };
+        Ok(updated == 1)
+    }
+
+    async fn delete_value(&self, key: &str) -> Result<()> {
+        self.conn.client().await?.execute(&self.delete_value_sql, &[&key]).await?;
+        Ok(())
+    }
+}
+
+/// These need a PostgreSQL database, so they're ignored unless run with `--ignored` and
+/// `DATABASE_URL` set, e.g. `DATABASE_URL=postgres://localhost/test cargo test -- --ignored postgres`
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    use crate::config::Postgres;
+    use crate::mq::{MessageQueue, Outgoing, Received, NACK_DELAY};
+
+    use super::PostgresQueue;
+
+    const TOPIC: &str = "task";
+
+    /// Options for a table of its own, so that tests don't see each other's messages
+    fn options(visibility_timeout_ms: u64) -> Postgres {
+        Postgres {
+            table: format!("trampoline_test_{}", uuid::Uuid::new_v4().simple()),
+            visibility_timeout_ms,
+            poll_interval_ms: 50,
+        }
+    }
+
+    async fn connect(options: &Postgres) -> PostgresQueue {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set to run the Postgres tests");
+        PostgresQueue::connect(&url, &[TOPIC.to_owned()], options).await.unwrap()
+    }
+
+    async fn drop_tables(queue: PostgresQueue, options: &Postgres) {
+        let sql = format!(r#"DROP TABLE "{table}"; DROP TABLE "{table}_values""#, table = options.table);
+        queue.conn.client().await.unwrap().batch_execute(&sql).await.unwrap();
+    }
+
+    fn outgoing(payload: &str) -> Outgoing {
+        Outgoing { payload: payload.as_bytes().to_vec(), ..Default::default() }
+    }
+
+    async fn receive(queue: &PostgresQueue) -> Received {
+        tokio::time::timeout(Duration::from_secs(5), queue.receive()).await
+            .expect("no message received")
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn receives_nothing_for(queue: &PostgresQueue, duration: Duration) -> bool {
+        tokio::time::timeout(duration, queue.receive()).await.is_err()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn acked_message_is_deleted() {
+        let options = options(60_000);
+        let queue = connect(&options).await;
+        queue.publish(TOPIC, outgoing("task")).await.unwrap();
+        let msg = receive(&queue).await;
+        assert_eq!((msg.topic.as_str(), msg.payload.as_slice(), msg.redeliveries), (TOPIC, b"task".as_slice(), 0));
+        queue.ack(&msg).await.unwrap();
+        assert!(receives_nothing_for(&queue, Duration::from_millis(300)).await);
+        drop_tables(queue, &options).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn nacked_message_is_redelivered_after_the_nack_delay() {
+        let options = options(60_000);
+        let queue = connect(&options).await;
+        queue.publish(TOPIC, outgoing("task")).await.unwrap();
+        let msg = receive(&queue).await;
+        let nacked_at = SystemTime::now();
+        queue.nack(&msg).await.unwrap();
+
+        let redelivered = receive(&queue).await;
+        assert!(SystemTime::now() >= nacked_at + NACK_DELAY);
+        assert_eq!(redelivered.message_id, msg.message_id);
+        assert_eq!(redelivered.redeliveries, 1);
+        queue.ack(&redelivered).await.unwrap();
+        drop_tables(queue, &options).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn nack_at_redelivers_the_next_attempt_when_due() {
+        let options = options(60_000);
+        let queue = connect(&options).await;
+        queue.publish(TOPIC, outgoing("attempt 1")).await.unwrap();
+        let msg = receive(&queue).await;
+        let deliver_at = SystemTime::now() + Duration::from_secs(1);
+        let next = Outgoing {
+            properties: HashMap::from([("attempt".to_owned(), "2".to_owned())]),
+            ..outgoing("attempt 2")
+        };
+        queue.nack_at(&msg, next, deliver_at).await.unwrap();
+
+        let redelivered = receive(&queue).await;
+        assert!(SystemTime::now() >= deliver_at);
+        assert_eq!(redelivered.message_id, msg.message_id);
+        assert_eq!(redelivered.payload, b"attempt 2");
+        assert_eq!(redelivered.properties["attempt"], "2");
+        assert_eq!(redelivered.redeliveries, 0);
+        queue.ack(&redelivered).await.unwrap();
+        drop_tables(queue, &options).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn message_published_for_later_is_delivered_when_due() {
+        let options = options(60_000);
+        let queue = connect(&options).await;
+        let deliver_at = SystemTime::now() + Duration::from_secs(1);
+        queue.publish_at(TOPIC, outgoing("later"), deliver_at).await.unwrap();
+
+        let msg = receive(&queue).await;
+        assert!(SystemTime::now() >= deliver_at);
+        assert_eq!(msg.payload, b"later");
+        queue.ack(&msg).await.unwrap();
+        drop_tables(queue, &options).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn unacked_message_is_redelivered_after_the_visibility_timeout() {
+        let options = options(500);
+        let crashed = connect(&options).await;
+        crashed.publish(TOPIC, outgoing("task")).await.unwrap();
+        let msg = receive(&crashed).await;
+        // Dropping the queue stops it keeping the message hidden, as a crash would
+        drop(crashed);
+
+        let queue = connect(&options).await;
+        let redelivered = receive(&queue).await;
+        assert_eq!(redelivered.message_id, msg.message_id);
+        assert_eq!(redelivered.redeliveries, 1);
+        queue.ack(&redelivered).await.unwrap();
+        drop_tables(queue, &options).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn message_handled_for_longer_than_the_visibility_timeout_isnt_redelivered() {
+        let options = options(500);
+        let queue = connect(&options).await;
+        let other = connect(&options).await;
+        queue.publish(TOPIC, outgoing("slow task")).await.unwrap();
+        let msg = receive(&queue).await;
+
+        assert!(receives_nothing_for(&other, Duration::from_millis(1500)).await, "the message was redelivered while it was being handled");
+        queue.ack(&msg).await.unwrap();
+        drop_tables(queue, &options).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn compare_and_set_only_sets_the_expected_value() {
+        let options = options(60_000);
+        let queue = connect(&options).await;
+        assert!(queue.compare_and_set("key", None, "1").await.unwrap());
+        assert!(!queue.compare_and_set("key", None, "2").await.unwrap());
+        assert!(!queue.compare_and_set("key", Some("2"), "3").await.unwrap());
+        assert!(queue.compare_and_set("key", Some("1"), "3").await.unwrap());
+        assert_eq!(queue.get_value("key").await.unwrap().as_deref(), Some("3"));
+        queue.delete_value("key").await.unwrap();
+        assert_eq!(queue.get_value("key").await.unwrap(), None);
+        drop_tables(queue, &options).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn connects_again_after_losing_the_connection() {
+        let options = options(60_000);
+        let queue = connect(&options).await;
+        let _ = queue.conn.client().await.unwrap().batch_execute("SELECT pg_terminate_backend(pg_backend_pid())").await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        queue.publish(TOPIC, outgoing("task")).await.unwrap();
+        let msg = receive(&queue).await;
+        queue.ack(&msg).await.unwrap();
+        drop_tables(queue, &options).await;
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use pulsar::{
+    consumer::Message, message::proto::command_subscribe::SubType, producer, Consumer, MultiTopicProducer, Pulsar, TokioExecutor
+};
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+use super::{LocalValues, MessageQueue, Outgoing, Received};
+
+/// Apache Pulsar, with one topic per task type
+pub struct PulsarQueue {
+    pulsar: Pulsar<TokioExecutor>,
+    producer: Mutex<MultiTopicProducer<TokioExecutor>>,
+    /// MultiTopicProducer can't set a delivery time, so delayed messages go through per-topic producers
+    delayed_producers: Mutex<HashMap<String, pulsar::Producer<TokioExecutor>>>,
+    received: Mutex<mpsc::Receiver<Result<Received>>>,
+    settlements: mpsc::UnboundedSender<Settlement>,
+    /// Pulsar has nowhere to keep shared values, so each Dispatcher keeps its own
+    values: LocalValues,
+}
+
+/// A request to ack or nack a received message, which only the consumer task can do
+struct Settlement {
+    message_id: String,
+    ack: bool,
+    result: oneshot::Sender<Result<()>>,
+}
+
+impl PulsarQueue {
+    pub async fn connect(url: &str, topics: &[String]) -> Result<PulsarQueue> {
+        let pulsar: Pulsar<_> = Pulsar::builder(url, TokioExecutor).build().await?;
+
+        let consumer: Consumer<Vec<u8>, _> = pulsar
+            .consumer()
+            .with_topics(topics)
+            .with_consumer_name("trampoline-dispatcher")
+            // Pulsar only honours delayed delivery, which retries depend on, for shared subscriptions
+            .with_subscription_type(SubType::Shared)
+            .with_subscription("trampoline-dispatch")
+            .build()
+            .await?;
+
+        let producer = pulsar
+            .producer()
+            .with_name("trampoline-dispatcher")
+            .build_multi_topic();
+
+        let (received_tx, received_rx) = mpsc::channel(1);
+        let (settlements_tx, settlements_rx) = mpsc::unbounded_channel();
+        tokio::spawn(Self::consume(consumer, received_tx, settlements_rx));
+
+        Ok(PulsarQueue {
+            pulsar,
+            producer: Mutex::new(producer),
+            delayed_producers: Mutex::new(HashMap::new()),
+            received: Mutex::new(received_rx),
+            settlements: settlements_tx,
+            values: LocalValues::default(),
+        })
+    }
+
+    /// Owns the consumer, which needs &mut to ack, and receives only as fast as messages are taken.
+    ///
+    /// The pulsar crate doesn't expose the broker's redelivery count, so the consumer counts the
+    /// nacks of the messages it gets back itself. A message redelivered to another consumer, or
+    /// after the Dispatcher restarts, starts again from 0.
+    async fn consume(mut consumer: Consumer<Vec<u8>, TokioExecutor>, received: mpsc::Sender<Result<Received>>, mut settlements: mpsc::UnboundedReceiver<Settlement>) {
+        let mut pending: HashMap<String, Message<Vec<u8>>> = HashMap::new();
+        let mut nacks: HashMap<String, u32> = HashMap::new();
+        let mut slot = None;
+        loop {
+            tokio::select! {
+                biased;
+                Some(settlement) = settlements.recv() => {
+                    let result = match pending.remove(&settlement.message_id) {
+                        Some(msg) if settlement.ack => {
+                            nacks.remove(&settlement.message_id);
+                            consumer.ack(&msg).await.map_err(anyhow::Error::from)
+                        },
+                        Some(msg) => {
+                            *nacks.entry(settlement.message_id).or_default() += 1;
+                            consumer.nack(&msg).await.map_err(anyhow::Error::from)
+                        },
+                        None => Err(anyhow!("message {} is not pending", settlement.message_id)),
+                    };
+                    let _ = settlement.result.send(result);
+                },
+                reserved = received.clone().reserve_owned(), if slot.is_none() => {
+                    match reserved {
+                        Ok(reserved) => slot = Some(reserved),
+                        // The queue has been dropped
+                        Err(_) => break,
+                    }
+                },
+                msg = consumer.try_next(), if slot.is_some() => {
+                    let slot = slot.take().expect("slot was checked");
+                    match msg {
+                        Ok(Some(msg)) => {
+                            let id = &msg.message_id.id;
+                            let mut message_id = format!("{}:{}:{}:{}", &msg.topic, id.ledger_id, id.entry_id, id.partition.unwrap_or(-1));
+                            if let Some(batch_index) = id.batch_index {
+                                message_id = format!("{}:{}", message_id, batch_index);
+                            }
+                            let received = Received {
+                                topic: msg.topic.clone(),
+                                message_id: message_id.clone(),
+                                payload: msg.payload.data.clone(),
+                                properties: msg.metadata().properties.iter().map(|kv| (kv.key.clone(), kv.value.clone())).collect(),
+                                redeliveries: nacks.get(&message_id).copied().unwrap_or(0),
+                            };
+                            pending.insert(message_id, msg);
+                            slot.send(Ok(received));
+                        },
+                        Ok(None) => break,
+                        Err(e) => {
+                            slot.send(Err(e.into()));
+                            break;
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    async fn settle(&self, msg: &Received, ack: bool) -> Result<()> {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.settlements
+            .send(Settlement { message_id: msg.message_id.clone(), ack, result: result_tx })
+            .map_err(|_| anyhow!("pulsar consumer has stopped"))?;
+        result_rx.await.context("pulsar consumer has stopped")?
+    }
+}
+
+#[async_trait]
+impl MessageQueue for PulsarQueue {
+    async fn receive(&self) -> Result<Option<Received>> {
+        self.received.lock().await.recv().await.transpose()
+    }
+
+    async fn ack(&self, msg: &Received) -> Result<()> {
+        self.settle(msg, true).await
+    }
+
+    async fn nack(&self, msg: &Received) -> Result<()> {
+        self.settle(msg, false).await
+    }
+
+    async fn publish(&self, topic: &str, msg: Outgoing) -> Result<()> {
+        let msg = producer::Message {
+            payload: msg.payload,
+            properties: msg.properties,
+            ..Default::default()
+        };
+        let receipt = self.producer.lock().await
+            .send(topic, msg).await
+            .context("sending message to topic failed")?;
+        receipt.await.context("message was not confirmed")?;
+        Ok(())
+    }
+
+    async fn publish_at(&self, topic: &str, msg: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        let receipt = {
+            let mut delayed_producers = self.delayed_producers.lock().await;
+            if !delayed_producers.contains_key(topic) {
+                let producer = self.pulsar
+                    .producer()
+                    .with_topic(topic)
+                    .build()
+                    .await
+                    .context("creating producer for delayed messages failed")?;
+                delayed_producers.insert(topic.to_owned(), producer);
+            }
+            let producer = delayed_producers.get_mut(topic).expect("producer was just inserted");
+            let mut builder = producer.create_message()
+                .with_content(msg.payload)
+                .deliver_at(deliver_at)?;
+            for (key, value) in msg.properties {
+                builder = builder.with_property(key, value);
+            }
+            builder.send().await.context("sending delayed message to topic failed")?
+        };
+        receipt.await.context("delayed message was not confirmed")?;
+        Ok(())
+    }
+
+    /// Values are kept by each Dispatcher, so only one of them may run schedules, callbacks
+    /// and shared rate limits
+    fn shares_values(&self) -> bool {
+        false
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.values.get(key))
+    }
+
+    async fn compare_and_set(&self, key: &str, expected: Option<&str>, value: &str) -> Result<bool> {
+        Ok(self.values.compare_and_set(key, expected, value))
+    }
+
+    async fn delete_value(&self, key: &str) -> Result<()> {
+        self.values.delete(key);
+        Ok(())
+    }
+}
@@ -0,0 +1,537 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::streams::{StreamId, StreamRangeReply, StreamReadOptions, StreamReadReply};
+use redis::{AsyncCommands, Client, RedisError, Script, Value};
+use tokio::sync::Mutex;
+
+use crate::config::Redis;
+
+use super::{MessageQueue, Outgoing, Pauses, Received, NACK_DELAY};
+
+/// Sorted set of delayed message IDs scored by delivery time, with each message held in a hash
+/// at `{DELAYED_KEY}:{id}` until it's due
+const DELAYED_KEY: &str = "trampoline:delayed";
+
+/// Moves due delayed messages onto their streams, with every field they were held with but the
+/// topic. It's a script so that with several Dispatchers, each delayed message is moved exactly once.
+const DELIVER_DUE_SCRIPT: &str = r"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, id in ipairs(due) do
+    local key = KEYS[1] .. ':' .. id
+    local held = redis.call('HGETALL', key)
+    local topic
+    local fields = {}
+    for i = 1, #held, 2 do
+        if held[i] == 'topic' then
+            topic = held[i + 1]
+        else
+            table.insert(fields, held[i])
+            table.insert(fields, held[i + 1])
+        end
+    end
+    if topic and #fields > 0 then
+        redis.call('XADD', topic, '*', unpack(fields))
+    end
+    redis.call('DEL', key)
+    redis.call('ZREM', KEYS[1], id)
+end
+return #due
+";
+
+/// Holds stream entry ARGV[2] of stream KEYS[1] as delayed message ARGV[3] in KEYS[2], due at
+/// ARGV[4], then acks and deletes the entry from group ARGV[1]. The entry keeps its properties,
+/// and either has its payload replaced by ARGV[6] if ARGV[5] is set, starting its redeliveries
+/// again, or counts one more redelivery.
+const NACK_SCRIPT: &str = r"
+local entry = redis.call('XRANGE', KEYS[1], ARGV[2], ARGV[2])[1]
+if entry then
+    local key = KEYS[2] .. ':' .. ARGV[3]
+    local fields = entry[2]
+    local redeliveries = 0
+    redis.call('HSET', key, 'topic', KEYS[1])
+    for i = 1, #fields, 2 do
+        if fields[i] == 'redeliveries' then
+            redeliveries = tonumber(fields[i + 1]) or 0
+        else
+            redis.call('HSET', key, fields[i], fields[i + 1])
+        end
+    end
+    if ARGV[5] ~= '' then
+        redis.call('HSET', key, 'payload', ARGV[6], 'redeliveries', 0)
+    else
+        redis.call('HSET', key, 'redeliveries', redeliveries + 1)
+    end
+    redis.call('ZADD', KEYS[2], ARGV[4], ARGV[3])
+end
+redis.call('XACK', KEYS[1], ARGV[1], ARGV[2])
+redis.call('XDEL', KEYS[1], ARGV[2])
+return 0
+";
+
+const DELIVER_DUE_BATCH: usize = 100;
+
+/// Prefix of the keys holding shared values
+const VALUES_KEY: &str = "trampoline:values";
+
+/// Sets a key to ARGV[3] if it's unset and ARGV[1] is empty, or if it holds ARGV[2] and ARGV[1] is set
+const COMPARE_AND_SET_SCRIPT: &str = r"
+local current = redis.call('GET', KEYS[1])
+if (ARGV[1] == '' and not current) or (ARGV[1] ~= '' and current == ARGV[2]) then
+    redis.call('SET', KEYS[1], ARGV[3])
+    return 1
+end
+return 0
+";
+
+/// Entries read at a time. Kept small because entries wait in the buffer while counting towards the claim idle time.
+const READ_BATCH: usize = 10;
+
+/// Redis Streams, with one stream per task type read through a consumer group
+pub struct RedisQueue {
+    topics: Vec<String>,
+    paused: Pauses,
+    group: String,
+    consumer: String,
+    claim_idle: Duration,
+    /// Commands other than the blocking read, which would otherwise hold them up
+    conn: ConnectionManager,
+    reader: Mutex<Reader>,
+    /// Entries read by this consumer and not yet settled, by topic and entry ID, which are
+    /// claimed afresh every so often so that they never sit idle for `claim_idle`
+    leased: Arc<std::sync::Mutex<HashSet<(String, String)>>>,
+}
+
+struct Reader {
+    conn: ConnectionManager,
+    /// Entries already read but not yet returned from receive
+    buffered: VecDeque<Received>,
+    last_claim: Instant,
+}
+
+impl RedisQueue {
+    pub async fn connect(url: &str, topics: &[String], options: &Redis) -> Result<RedisQueue> {
+        let client = Client::open(url)?;
+        let mut conn = client.get_connection_manager().await?;
+        let reader_conn = client.get_connection_manager().await?;
+
+        for topic in topics {
+            // Start from the beginning of the stream, so tasks published before the group existed are processed
+            let created: Result<(), RedisError> = conn.xgroup_create_mkstream(topic, &options.group, "0").await;
+            match created {
+                Ok(()) => {},
+                Err(e) if e.code() == Some("BUSYGROUP") => {},
+                Err(e) => return Err(e).context(format!("creating consumer group for stream `{}` failed", topic)),
+            }
+        }
+
+        let leased = Arc::new(std::sync::Mutex::new(HashSet::new()));
+        let claim_idle = Duration::from_millis(options.claim_idle_ms);
+        tokio::spawn(Self::keep_leased(conn.clone(), Arc::downgrade(&leased), options.group.clone(), options.consumer.clone(), claim_idle / 2));
+        let queue = RedisQueue {
+            topics: topics.to_vec(),
+            paused: Pauses::default(),
+            group: options.group.clone(),
+            consumer: options.consumer.clone(),
+            claim_idle,
+            conn: conn.clone(),
+            reader: Mutex::new(Reader {
+                conn: reader_conn,
+                buffered: VecDeque::new(),
+                last_claim: Instant::now(),
+            }),
+            leased,
+        };
+        tokio::spawn(Self::deliver_delayed(conn, Duration::from_millis(options.delayed_poll_ms)));
+        Ok(queue)
+    }
+
+    /// Periodically moves delayed messages that are due onto their streams
+    async fn deliver_delayed(mut conn: ConnectionManager, poll: Duration) {
+        let script = Script::new(DELIVER_DUE_SCRIPT);
+        loop {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+            let delivered: Result<usize, RedisError> = script
+                .key(DELAYED_KEY)
+                .arg(now)
+                .arg(DELIVER_DUE_BATCH)
+                .invoke_async(&mut conn)
+                .await;
+            match delivered {
+                // There may be more due right away
+                Ok(n) if n == DELIVER_DUE_BATCH => continue,
+                Ok(_) => {},
+                Err(e) => log::error!("delivering delayed messages failed: {:?}", e),
+            }
+            tokio::time::sleep(poll).await;
+        }
+    }
+
+    /// Claims the entries this consumer holds again each interval, which resets their idle
+    /// time, for as long as the queue is alive. Otherwise a task that takes longer than
+    /// `claim_idle` would be claimed by another consumer, or this one, and run twice.
+    async fn keep_leased(mut conn: ConnectionManager, leased: Weak<std::sync::Mutex<HashSet<(String, String)>>>, group: String, consumer: String, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(leased) = leased.upgrade() else {
+                return;
+            };
+            let mut by_topic: HashMap<String, Vec<String>> = HashMap::new();
+            for (topic, id) in leased.lock().unwrap().iter() {
+                by_topic.entry(topic.clone()).or_default().push(id.clone());
+            }
+            drop(leased);
+            for (topic, ids) in by_topic {
+                // Entries settled meanwhile are no longer pending, so claiming them does nothing
+                let claimed: Result<Value, RedisError> = redis::cmd("XCLAIM")
+                    .arg(&topic)
+                    .arg(&group)
+                    .arg(&consumer)
+                    .arg(0)
+                    .arg(&ids)
+                    .arg("JUSTID")
+                    .query_async(&mut conn)
+                    .await;
+                if let Err(e) = claimed {
+                    log::error!("keeping messages on stream `{}` claimed failed: {:?}", topic, e);
+                }
+            }
+        }
+    }
+
+    /// Buffers an entry to be received, and keeps it claimed until it's settled
+    fn buffer(&self, reader: &mut Reader, topic: &str, entry: &StreamId, claimed: bool) {
+        self.leased.lock().unwrap().insert((topic.to_owned(), entry.id.clone()));
+        reader.buffered.push_back(Self::to_received(topic, entry, claimed));
+    }
+
+    /// Stops keeping a settled entry claimed
+    fn release(&self, msg: &Received) {
+        self.leased.lock().unwrap().remove(&(msg.topic.clone(), Self::entry_id(msg).to_owned()));
+    }
+
+    /// An entry as a received message, counting one more redelivery if it was claimed from another consumer
+    fn to_received(topic: &str, entry: &StreamId, claimed: bool) -> Received {
+        Received {
+            topic: topic.to_owned(),
+            message_id: format!("{}:{}", topic, entry.id),
+            payload: entry.get::<Vec<u8>>("payload").unwrap_or_default(),
+            properties: entry.get::<Vec<u8>>("properties")
+                .and_then(|properties| serde_json::from_slice(&properties).ok())
+                .unwrap_or_default(),
+            redeliveries: entry.get::<u32>("redeliveries").unwrap_or(0) + claimed as u32,
+        }
+    }
+
+    /// The stream entry ID of a received message
+    fn entry_id(msg: &Received) -> &str {
+        msg.message_id.rsplit_once(':').map(|(_, id)| id).unwrap_or(&msg.message_id)
+    }
+
+    /// Takes over entries that another consumer read but hasn't acked within the idle time,
+    /// e.g. because its Dispatcher crashed
+    async fn claim_stuck(&self, reader: &mut Reader) -> Result<()> {
+        for topic in &self.paused.active(&self.topics) {
+            let reply: Value = redis::cmd("XAUTOCLAIM")
+                .arg(topic)
+                .arg(&self.group)
+                .arg(&self.consumer)
+                .arg(self.claim_idle.as_millis() as u64)
+                .arg("0-0")
+                .arg("COUNT")
+                .arg(READ_BATCH)
+                .query_async(&mut reader.conn)
+                .await?;
+            // The reply is [next start ID, entries, deleted IDs (Redis 7+)]
+            if let Value::Bulk(items) = reply {
+                if let Some(entries) = items.get(1) {
+                    let entries: StreamRangeReply = redis::from_redis_value(entries)?;
+                    for entry in &entries.ids {
+                        log::warn!("claimed stuck message {}:{}", topic, entry.id);
+                        self.buffer(reader, topic, entry, true);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Holds an entry for delivery again at `deliver_at`, acking and deleting it in the same
+    /// script. The held copy is given `payload` in place of its own, if set.
+    async fn hold(&self, msg: &Received, payload: Option<Vec<u8>>, deliver_at: SystemTime) -> Result<()> {
+        self.release(msg);
+        let mut conn = self.conn.clone();
+        Script::new(NACK_SCRIPT)
+            .key(&msg.topic)
+            .key(DELAYED_KEY)
+            .arg(&self.group)
+            .arg(Self::entry_id(msg))
+            .arg(uuid::Uuid::new_v4().to_string())
+            .arg(Self::score(deliver_at)?)
+            .arg(if payload.is_some() { "1" } else { "" })
+            .arg(payload.unwrap_or_default())
+            .invoke_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    fn score(deliver_at: SystemTime) -> Result<u64> {
+        Ok(deliver_at.duration_since(UNIX_EPOCH)?.as_millis() as u64)
+    }
+}
+
+#[async_trait]
+impl MessageQueue for RedisQueue {
+    async fn receive(&self) -> Result<Option<Received>> {
+        let mut reader = self.reader.lock().await;
+        loop {
+            if let Some(received) = reader.buffered.pop_front() {
+                return Ok(Some(received));
+            }
+            if reader.last_claim.elapsed() >= self.claim_idle / 2 {
+                reader.last_claim = Instant::now();
+                if let Err(e) = self.claim_stuck(&mut reader).await {
+                    log::error!("claiming stuck messages failed: {:?}", e);
+                }
+                continue;
+            }
+            let topics = self.paused.active(&self.topics);
+            if topics.is_empty() {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            let options = StreamReadOptions::default()
+                .group(&self.group, &self.consumer)
+                .count(READ_BATCH)
+                .block(1000);
+            let ids = vec![">"; topics.len()];
+            let reply: Option<StreamReadReply> = reader.conn.xread_options(&topics, &ids, &options).await?;
+            for stream in reply.map(|r| r.keys).unwrap_or_default() {
+                for entry in &stream.ids {
+                    self.buffer(&mut reader, &stream.key, entry, false);
+                }
+            }
+        }
+    }
+
+    async fn ack(&self, msg: &Received) -> Result<()> {
+        self.release(msg);
+        let id = Self::entry_id(msg);
+        let mut conn = self.conn.clone();
+        redis::pipe()
+            .atomic()
+            .xack(&msg.topic, &self.group, &[id]).ignore()
+            .xdel(&msg.topic, &[id]).ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Holds the entry as a delayed message with its properties, since a stream entry can't be
+    /// made invisible for a while
+    async fn nack(&self, msg: &Received) -> Result<()> {
+        self.hold(msg, None, SystemTime::now() + NACK_DELAY).await
+    }
+
+    /// Holds the entry with `next` as its payload, which carries the attempt count in place of
+    /// the redeliveries. It's delivered with a new entry ID, as anything added to a stream is.
+    async fn nack_at(&self, msg: &Received, next: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        self.hold(msg, Some(next.payload), deliver_at).await
+    }
+
+    fn pause(&self, topic: &str, until: SystemTime) {
+        self.paused.pause(topic, until);
+    }
+
+    async fn publish(&self, topic: &str, msg: Outgoing) -> Result<()> {
+        let properties = serde_json::to_vec(&msg.properties)?;
+        let mut conn = self.conn.clone();
+        let _: String = conn.xadd(topic, "*", &[("payload", msg.payload), ("properties", properties)]).await?;
+        Ok(())
+    }
+
+    async fn publish_at(&self, topic: &str, msg: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let score = Self::score(deliver_at)?;
+        let properties = serde_json::to_vec(&msg.properties)?;
+        let fields: HashMap<&str, Vec<u8>> = HashMap::from([
+            ("topic", topic.as_bytes().to_vec()),
+            ("payload", msg.payload),
+            ("properties", properties),
+        ]);
+        let mut conn = self.conn.clone();
+        redis::pipe()
+            .atomic()
+            .hset_multiple(format!("{}:{}", DELAYED_KEY, id), &fields.into_iter().collect::<Vec<_>>()).ignore()
+            .zadd(DELAYED_KEY, &id, score).ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<String>> {
+        let mut conn = self.conn.clone();
+        Ok(conn.get(format!("{}:{}", VALUES_KEY, key)).await?)
+    }
+
+    async fn compare_and_set(&self, key: &str, expected: Option<&str>, value: &str) -> Result<bool> {
+        let mut conn = self.conn.clone();
+        let set: bool = Script::new(COMPARE_AND_SET_SCRIPT)
+            .key(format!("{}:{}", VALUES_KEY, key))
+            .arg(if expected.is_some() { "1" } else { "" })
+            .arg(expected.unwrap_or_default())
+            .arg(value)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(set)
+    }
+
+    async fn delete_value(&self, key: &str) -> Result<()> {
+        let mut conn = self.conn.clone();
+        conn.del::<_, ()>(format!("{}:{}", VALUES_KEY, key)).await?;
+        Ok(())
+    }
+}
+
+/// These need a Redis server, so they're ignored unless run with `--ignored` and `REDIS_URL` set,
+/// e.g. `REDIS_URL=redis://localhost cargo test -- --ignored redis`
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    use redis::AsyncCommands;
+    use redis::streams::StreamRangeReply;
+
+    use crate::config::Redis;
+    use crate::mq::{MessageQueue, Outgoing, Received};
+
+    use super::RedisQueue;
+
+    /// A queue on a fresh stream, so that tests don't see each other's messages
+    async fn connect(topic: &str, consumer: &str) -> RedisQueue {
+        let url = std::env::var("REDIS_URL").expect("REDIS_URL must be set to run the Redis tests");
+        let options = format!("consumer = {:?}\nclaim_idle_ms = 500\ndelayed_poll_ms = 50", consumer);
+        RedisQueue::connect(&url, &[topic.to_owned()], &toml::from_str::<Redis>(&options).unwrap()).await.unwrap()
+    }
+
+    fn topic() -> String {
+        format!("trampoline-test-{}", uuid::Uuid::new_v4())
+    }
+
+    fn outgoing(payload: &str) -> Outgoing {
+        Outgoing {
+            payload: payload.as_bytes().to_vec(),
+            properties: HashMap::from([("origin".to_owned(), "test".to_owned())]),
+        }
+    }
+
+    async fn receive(queue: &RedisQueue) -> Received {
+        tokio::time::timeout(Duration::from_secs(5), queue.receive()).await
+            .expect("no message received")
+            .unwrap()
+            .unwrap()
+    }
+
+    /// The entries in a stream, with their fields
+    async fn entries(queue: &RedisQueue, topic: &str) -> Vec<HashMap<String, String>> {
+        let mut conn = queue.conn.clone();
+        let reply: StreamRangeReply = conn.xrange_all(topic).await.unwrap();
+        reply.ids.iter()
+            .map(|entry| entry.map.keys().map(|key| (key.clone(), entry.get::<String>(key).unwrap())).collect())
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn acked_message_is_deleted() {
+        let topic = topic();
+        let queue = connect(&topic, "a").await;
+        queue.publish(&topic, outgoing("task")).await.unwrap();
+        let msg = receive(&queue).await;
+        assert_eq!(msg.payload, b"task");
+        assert_eq!(msg.redeliveries, 0);
+        queue.ack(&msg).await.unwrap();
+        assert!(entries(&queue, &topic).await.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn nacked_message_is_redelivered_with_its_properties() {
+        let topic = topic();
+        let queue = connect(&topic, "a").await;
+        queue.publish(&topic, outgoing("task")).await.unwrap();
+        let msg = receive(&queue).await;
+        queue.nack(&msg).await.unwrap();
+        assert!(entries(&queue, &topic).await.is_empty());
+
+        let redelivered = receive(&queue).await;
+        assert_ne!(redelivered.message_id, msg.message_id);
+        assert_eq!(redelivered.payload, b"task");
+        assert_eq!(redelivered.redeliveries, 1);
+        let entries = entries(&queue, &topic).await;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["properties"], r#"{"origin":"test"}"#);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn nack_at_redelivers_the_next_attempt_when_due() {
+        let topic = topic();
+        let queue = connect(&topic, "a").await;
+        queue.publish(&topic, outgoing("attempt 1")).await.unwrap();
+        let msg = receive(&queue).await;
+        queue.nack(&msg).await.unwrap();
+        let msg = receive(&queue).await;
+        let deliver_at = SystemTime::now() + Duration::from_secs(2);
+        queue.nack_at(&msg, outgoing("attempt 2"), deliver_at).await.unwrap();
+
+        let redelivered = receive(&queue).await;
+        assert!(SystemTime::now() >= deliver_at);
+        assert_eq!(redelivered.payload, b"attempt 2");
+        // The payload counts the attempts now
+        assert_eq!(redelivered.redeliveries, 0);
+        assert_eq!(entries(&queue, &topic).await[0]["properties"], r#"{"origin":"test"}"#);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn message_left_unacked_is_claimed_by_another_consumer() {
+        let topic = topic();
+        let crashed = connect(&topic, "a").await;
+        let queue = connect(&topic, "b").await;
+        queue.publish(&topic, outgoing("task")).await.unwrap();
+        let msg = receive(&crashed).await;
+
+        let claimed = receive(&queue).await;
+        assert_eq!(claimed.message_id, msg.message_id);
+        assert_eq!(claimed.redeliveries, 1);
+        queue.ack(&claimed).await.unwrap();
+        assert!(entries(&queue, &topic).await.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn message_handled_for_longer_than_claim_idle_isnt_claimed() {
+        let topic = topic();
+        let queue = connect(&topic, "a").await;
+        let other = connect(&topic, "b").await;
+        queue.publish(&topic, outgoing("slow task")).await.unwrap();
+        let msg = receive(&queue).await;
+
+        // Three times the claim idle time, during which neither consumer may take it back
+        let claimed = tokio::time::timeout(Duration::from_millis(1500), async {
+            tokio::select! {
+                msg = other.receive() => msg,
+                msg = queue.receive() => msg,
+            }
+        }).await;
+        assert!(claimed.is_err(), "the message was claimed while it was being handled");
+        queue.ack(&msg).await.unwrap();
+        assert!(entries(&queue, &topic).await.is_empty());
+    }
+}
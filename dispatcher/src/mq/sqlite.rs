@@ -0,0 +1,395 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::sync::Notify;
+
+use crate::config::Sqlite;
+
+use super::{MessageQueue, Outgoing, Pauses, Received, NACK_DELAY};
+
+/// A table of messages in an SQLite database file, so that the Dispatcher can run on its own
+/// without a broker. Works like the postgres backend: received messages are hidden for a
+/// visibility timeout while they're processed, and deleted when acked.
+pub struct SqliteQueue {
+    conn: Arc<Mutex<Connection>>,
+    topics: Vec<String>,
+    paused: Pauses,
+    visibility_timeout: Duration,
+    poll_interval: Duration,
+    /// Rows claimed by this Dispatcher and not yet settled, which are hidden for another
+    /// visibility timeout every so often. It's locked while they are, so that a row settled
+    /// meanwhile isn't hidden again.
+    leased: Arc<tokio::sync::Mutex<HashSet<i64>>>,
+    /// Woken whenever a message is published by this Dispatcher
+    available: Notify,
+}
+
+impl SqliteQueue {
+    pub fn open(topics: &[String], options: &Sqlite) -> Result<SqliteQueue> {
+        let conn = Connection::open(&options.path)
+            .context(format!("opening sqlite database `{}` failed", options.path))?;
+        // Other processes, such as a second Dispatcher on the same file, may hold the write lock briefly
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(r#"
+            PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS trampoline_tasks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                topic TEXT NOT NULL,
+                payload BLOB NOT NULL,
+                properties TEXT NOT NULL DEFAULT '{}',
+                visible_at INTEGER NOT NULL,
+                deliveries INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE INDEX IF NOT EXISTS trampoline_tasks_topic_visible_at ON trampoline_tasks (topic, visible_at);
+            CREATE TABLE IF NOT EXISTS trampoline_values (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+        "#).context("creating table `trampoline_tasks` failed")?;
+
+        let conn = Arc::new(Mutex::new(conn));
+        let visibility_timeout = Duration::from_millis(options.visibility_timeout_ms);
+        let leased = Arc::new(tokio::sync::Mutex::new(HashSet::new()));
+        tokio::spawn(Self::keep_leased(conn.clone(), Arc::downgrade(&leased), visibility_timeout));
+        Ok(SqliteQueue {
+            conn,
+            topics: topics.to_vec(),
+            paused: Pauses::default(),
+            visibility_timeout,
+            leased,
+            poll_interval: Duration::from_millis(options.poll_interval_ms),
+            available: Notify::new(),
+        })
+    }
+
+    /// Runs a statement on a blocking thread, since SQLite does its I/O synchronously
+    async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        Self::call_on(self.conn.clone(), f).await
+    }
+
+    async fn call_on<T, F>(conn: Arc<Mutex<Connection>>, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let result = tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().expect("sqlite connection lock poisoned");
+            f(&conn)
+        }).await?;
+        Ok(result?)
+    }
+
+    /// Hides the rows this Dispatcher has claimed for another visibility timeout every half of
+    /// one, for as long as the queue is alive. Otherwise a task that takes longer than the
+    /// timeout would be claimed again and run twice.
+    async fn keep_leased(conn: Arc<Mutex<Connection>>, leased: Weak<tokio::sync::Mutex<HashSet<i64>>>, visibility_timeout: Duration) {
+        loop {
+            tokio::time::sleep(visibility_timeout / 2).await;
+            let Some(leased) = leased.upgrade() else {
+                return;
+            };
+            let leased = leased.lock().await;
+            if leased.is_empty() {
+                continue;
+            }
+            let ids = serde_json::to_string(&*leased).unwrap_or_default();
+            let hidden_until = Self::millis(SystemTime::now() + visibility_timeout);
+            let extended = Self::call_on(conn.clone(), move |conn| conn.execute(
+                "UPDATE trampoline_tasks SET visible_at = ?1 WHERE id IN (SELECT value FROM json_each(?2))",
+                params![hidden_until, ids],
+            )).await;
+            if let Err(e) = extended {
+                log::error!("extending the visibility timeout of claimed messages failed: {:?}", e);
+            }
+        }
+    }
+
+    /// Stops hiding a settled row, returning its ID
+    async fn release(&self, msg: &Received) -> Result<i64> {
+        let id = Self::row_id(msg)?;
+        self.leased.lock().await.remove(&id);
+        Ok(id)
+    }
+
+    /// Milliseconds since the epoch, which is how times are stored
+    fn millis(time: SystemTime) -> i64 {
+        time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+    }
+
+    /// The row ID of a received message
+    fn row_id(msg: &Received) -> Result<i64> {
+        let id = msg.message_id.rsplit_once(':').map(|(_, id)| id).unwrap_or(&msg.message_id);
+        id.parse().context(format!("invalid message id `{}`", msg.message_id))
+    }
+
+    async fn insert(&self, topic: &str, msg: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        let topic = topic.to_owned();
+        let properties = serde_json::to_string(&msg.properties)?;
+        let visible_at = Self::millis(deliver_at);
+        self.call(move |conn| conn.execute(
+            "INSERT INTO trampoline_tasks (topic, payload, properties, visible_at) VALUES (?1, ?2, ?3, ?4)",
+            params![topic, msg.payload, properties, visible_at],
+        )).await?;
+        self.available.notify_one();
+        Ok(())
+    }
+
+    async fn claim(&self) -> Result<Option<Received>> {
+        let active = self.paused.active(&self.topics);
+        if active.is_empty() {
+            return Ok(None);
+        }
+        // A JSON array, for matching with `json_each`
+        let topics = serde_json::to_string(&active)?;
+        let now = SystemTime::now();
+        let (now, hidden_until) = (Self::millis(now), Self::millis(now + self.visibility_timeout));
+        let row = self.call(move |conn| conn.query_row(
+            "UPDATE trampoline_tasks
+             SET visible_at = ?1, deliveries = deliveries + 1
+             WHERE id = (
+                 SELECT id FROM trampoline_tasks
+                 WHERE topic IN (SELECT value FROM json_each(?2)) AND visible_at <= ?3
+                 ORDER BY visible_at, id
+                 LIMIT 1
+             )
+             RETURNING id, topic, payload, properties, deliveries",
+            params![hidden_until, topics, now],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Vec<u8>>(2)?, row.get::<_, String>(3)?, row.get::<_, u32>(4)?)),
+        ).optional()).await?;
+        if let Some((id, ..)) = row {
+            self.leased.lock().await.insert(id);
+        }
+        let Some((id, topic, payload, properties, deliveries)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Received {
+            message_id: format!("{}:{}", topic, id),
+            topic,
+            payload,
+            properties: serde_json::from_str(&properties)?,
+            redeliveries: deliveries.saturating_sub(1),
+        }))
+    }
+}
+
+#[async_trait]
+impl MessageQueue for SqliteQueue {
+    async fn receive(&self) -> Result<Option<Received>> {
+        loop {
+            if let Some(received) = self.claim().await? {
+                return Ok(Some(received));
+            }
+            // Polling picks up delayed messages once they're due, and messages from other processes
+            let _ = tokio::time::timeout(self.poll_interval, self.available.notified()).await;
+        }
+    }
+
+    async fn ack(&self, msg: &Received) -> Result<()> {
+        let id = self.release(msg).await?;
+        self.call(move |conn| conn.execute("DELETE FROM trampoline_tasks WHERE id = ?1", params![id])).await?;
+        Ok(())
+    }
+
+    async fn nack(&self, msg: &Received) -> Result<()> {
+        let id = self.release(msg).await?;
+        let visible_at = Self::millis(SystemTime::now() + NACK_DELAY);
+        self.call(move |conn| conn.execute("UPDATE trampoline_tasks SET visible_at = ?1 WHERE id = ?2", params![visible_at, id])).await?;
+        Ok(())
+    }
+
+    /// Redelivers the row itself with `next` as its payload, which carries the attempt count
+    /// in place of the deliveries, so they start again from 0
+    async fn nack_at(&self, msg: &Received, next: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        let id = self.release(msg).await?;
+        let properties = serde_json::to_string(&next.properties)?;
+        let visible_at = Self::millis(deliver_at);
+        self.call(move |conn| conn.execute(
+            "UPDATE trampoline_tasks SET payload = ?1, properties = ?2, visible_at = ?3, deliveries = 0 WHERE id = ?4",
+            params![next.payload, properties, visible_at, id],
+        )).await?;
+        Ok(())
+    }
+
+    fn pause(&self, topic: &str, until: SystemTime) {
+        self.paused.pause(topic, until);
+    }
+
+    async fn publish(&self, topic: &str, msg: Outgoing) -> Result<()> {
+        self.insert(topic, msg, SystemTime::now()).await
+    }
+
+    async fn publish_at(&self, topic: &str, msg: Outgoing, deliver_at: SystemTime) -> Result<()> {
+        self.insert(topic, msg, deliver_at).await
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<String>> {
+        let key = key.to_owned();
+        self.call(move |conn| conn.query_row(
+            "SELECT value FROM trampoline_values WHERE key = ?1", params![key], |row| row.get(0),
+        ).optional()).await
+    }
+
+    async fn compare_and_set(&self, key: &str, expected: Option<&str>, value: &str) -> Result<bool> {
+        let (key, expected, value) = (key.to_owned(), expected.map(str::to_owned), value.to_owned());
+        let updated = self.call(move |conn| match expected {
+            None => conn.execute("INSERT INTO trampoline_values (key, value) VALUES (?1, ?2) ON CONFLICT DO NOTHING", params![key, value]),
+            Some(expected) => conn.execute("UPDATE trampoline_values SET value = ?2 WHERE key = ?1 AND value = ?3", params![key, value, expected]),
+        }).await?;
+        Ok(updated == 1)
+    }
+
+    async fn delete_value(&self, key: &str) -> Result<()> {
+        let key = key.to_owned();
+        self.call(move |conn| conn.execute("DELETE FROM trampoline_values WHERE key = ?1", params![key])).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use crate::config::Sqlite;
+    use crate::mq::{MessageQueue, Outgoing, Received};
+
+    use super::SqliteQueue;
+
+    const TOPIC: &str = "task";
+
+    /// A database file of its own, deleted once the test is done
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new() -> TempDb {
+            TempDb(std::env::temp_dir().join(format!("trampoline-test-{}.db", uuid::Uuid::new_v4().simple())))
+        }
+
+        fn open(&self, visibility_timeout_ms: u64) -> SqliteQueue {
+            let options = Sqlite {
+                path: self.0.to_string_lossy().into_owned(),
+                visibility_timeout_ms,
+                poll_interval_ms: 50,
+            };
+            SqliteQueue::open(&[TOPIC.to_owned()], &options).unwrap()
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.0.display(), suffix));
+            }
+        }
+    }
+
+    fn outgoing(payload: &str) -> Outgoing {
+        Outgoing { payload: payload.as_bytes().to_vec(), ..Default::default() }
+    }
+
+    async fn receive(queue: &SqliteQueue) -> Received {
+        tokio::time::timeout(Duration::from_secs(5), queue.receive()).await
+            .expect("no message received")
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn receives_nothing_for(queue: &SqliteQueue, duration: Duration) -> bool {
+        tokio::time::timeout(duration, queue.receive()).await.is_err()
+    }
+
+    #[tokio::test]
+    async fn acked_message_is_deleted() {
+        let db = TempDb::new();
+        let queue = db.open(60_000);
+        queue.publish(TOPIC, outgoing("task")).await.unwrap();
+        let msg = receive(&queue).await;
+        assert_eq!((msg.topic.as_str(), msg.payload.as_slice(), msg.redeliveries), (TOPIC, b"task".as_slice(), 0));
+        queue.ack(&msg).await.unwrap();
+        assert!(receives_nothing_for(&queue, Duration::from_millis(200)).await);
+    }
+
+    #[tokio::test]
+    async fn nack_at_redelivers_the_next_attempt_when_due() {
+        let db = TempDb::new();
+        let queue = db.open(60_000);
+        queue.publish(TOPIC, outgoing("attempt 1")).await.unwrap();
+        let msg = receive(&queue).await;
+        let deliver_at = SystemTime::now() + Duration::from_millis(300);
+        let next = Outgoing {
+            properties: HashMap::from([("attempt".to_owned(), "2".to_owned())]),
+            ..outgoing("attempt 2")
+        };
+        queue.nack_at(&msg, next, deliver_at).await.unwrap();
+
+        let redelivered = receive(&queue).await;
+        assert!(SystemTime::now() >= deliver_at);
+        assert_eq!(redelivered.message_id, msg.message_id);
+        assert_eq!(redelivered.payload, b"attempt 2");
+        assert_eq!(redelivered.properties["attempt"], "2");
+        assert_eq!(redelivered.redeliveries, 0);
+    }
+
+    #[tokio::test]
+    async fn message_published_for_later_is_delivered_when_due() {
+        let db = TempDb::new();
+        let queue = db.open(60_000);
+        let deliver_at = SystemTime::now() + Duration::from_millis(300);
+        queue.publish_at(TOPIC, outgoing("later"), deliver_at).await.unwrap();
+        queue.publish(TOPIC, outgoing("now")).await.unwrap();
+
+        assert_eq!(receive(&queue).await.payload, b"now");
+        let msg = receive(&queue).await;
+        assert!(SystemTime::now() >= deliver_at);
+        assert_eq!(msg.payload, b"later");
+    }
+
+    #[tokio::test]
+    async fn unacked_message_is_redelivered_after_the_visibility_timeout() {
+        let db = TempDb::new();
+        let crashed = db.open(300);
+        crashed.publish(TOPIC, outgoing("task")).await.unwrap();
+        let msg = receive(&crashed).await;
+        // Dropping the queue stops it keeping the message hidden, as a crash would
+        drop(crashed);
+
+        let queue = db.open(300);
+        let redelivered = receive(&queue).await;
+        assert_eq!(redelivered.message_id, msg.message_id);
+        assert_eq!(redelivered.redeliveries, 1);
+    }
+
+    #[tokio::test]
+    async fn message_handled_for_longer_than_the_visibility_timeout_isnt_redelivered() {
+        let db = TempDb::new();
+        let queue = db.open(300);
+        let other = db.open(300);
+        queue.publish(TOPIC, outgoing("slow task")).await.unwrap();
+        let msg = receive(&queue).await;
+
+        assert!(receives_nothing_for(&other, Duration::from_millis(1000)).await, "the message was redelivered while it was being handled");
+        queue.ack(&msg).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_outlast_the_queue() {
+        let db = TempDb::new();
+        let queue = db.open(60_000);
+        queue.publish(TOPIC, outgoing("task")).await.unwrap();
+        queue.compare_and_set("key", None, "value").await.unwrap();
+        drop(queue);
+
+        let queue = db.open(60_000);
+        assert_eq!(receive(&queue).await.payload, b"task");
+        assert_eq!(queue.get_value("key").await.unwrap().as_deref(), Some("value"));
+    }
+}
@@ -1,24 +1,53 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
 use anyhow::{Context, Result};
-use pulsar::{producer::SendFuture, Executor, MultiTopicProducer, Pulsar};
 
 use crate::data::DynamicTaskMessage;
+use crate::mq::{MessageQueue, Outgoing, Received};
 
 /// Producer for task messages that routes tasks based on their content
-pub struct Producer<Exe: Executor> {
-    producer: MultiTopicProducer<Exe>
+#[derive(Clone)]
+pub struct Producer {
+    mq: Arc<dyn MessageQueue>,
 }
 
-impl<Exe: Executor> Producer<Exe> {
-    pub fn new<E: Executor>(pulsar: &Pulsar<E>, name: &str) -> Producer<E> {
-        let producer = pulsar
-            .producer()
-            .with_name(name)
-            .build_multi_topic();
-        Producer { producer }
+impl Producer {
+    pub fn new(mq: Arc<dyn MessageQueue>) -> Producer {
+        Producer { mq }
+    }
+
+    /// Sends a task to the topic for its type, returning once the queue has confirmed it.
+    /// A task with `deliver_at` or `delay` set is delivered once it's due.
+    pub async fn send(&self, msg: &DynamicTaskMessage) -> Result<()> {
+        let mut msg = msg.clone();
+        if let Some(deliver_at) = msg.take_schedule() {
+            return self.send_at(&msg, deliver_at).await;
+        }
+        let topic = &msg.type_name;
+        self.mq.publish(topic, Self::outgoing(&msg)?).await.context("sending task to topic failed")
     }
 
-    pub async fn send(&mut self, msg: &DynamicTaskMessage) -> Result<SendFuture> {
+    /// Sends a task that is delivered to consumers no earlier than `deliver_at`
+    pub async fn send_at(&self, msg: &DynamicTaskMessage, deliver_at: SystemTime) -> Result<()> {
         let topic = &msg.type_name;
-        self.producer.send(topic, msg).await.context("sending task to topic failed")
+        self.mq.publish_at(topic, Self::outgoing(msg)?, deliver_at).await.context("sending delayed task to topic failed")
+    }
+
+    /// Has a received task redelivered no earlier than `deliver_at`, as `next` where the queue
+    /// doesn't redeliver the original message itself
+    pub async fn redeliver_at(&self, msg: &Received, next: &DynamicTaskMessage, deliver_at: SystemTime) -> Result<()> {
+        self.mq.nack_at(msg, Self::outgoing(next)?, deliver_at).await.context("scheduling redelivery of task failed")
     }
-}
\ No newline at end of file
+
+    /// Sends a raw payload, such as a message that couldn't be processed, to the given topic
+    pub async fn send_raw(&self, topic: &str, payload: Vec<u8>, properties: HashMap<String, String>) -> Result<()> {
+        self.mq.publish(topic, Outgoing { payload, properties }).await.context("sending message to topic failed")
+    }
+
+    fn outgoing(msg: &DynamicTaskMessage) -> Result<Outgoing> {
+        let payload = serde_json::to_vec(msg)?;
+        Ok(Outgoing { payload, ..Default::default() })
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+
+use crate::config::{CatchUp, Schedule};
+use crate::data::DynamicTaskMessage;
+use crate::mq::MessageQueue;
+use crate::producer::Producer;
+
+/// Ticks later than this are considered missed, for schedules that skip missed ticks
+const MISSED_AFTER: Duration = Duration::from_secs(60);
+
+/// Most missed ticks run at once for schedules that catch up on all of them
+const MAX_CATCH_UP: usize = 1000;
+
+/// How long to wait after failing to check a schedule before trying again
+const ERROR_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Publishes tasks on the schedules in `[[schedules]]`.
+///
+/// Each schedule's last tick is kept as a value shared through the message queue, which a
+/// Dispatcher has to advance with compare-and-set before publishing the tick's tasks, and puts
+/// back to the last tick it published if publishing fails. So each tick is published once
+/// however many Dispatchers are running, and after a restart the Dispatchers know which ticks
+/// were missed.
+pub struct Scheduler {
+    mq: Arc<dyn MessageQueue>,
+    producer: Producer,
+    schedules: Vec<(Schedule, cron::Schedule)>,
+}
+
+impl Scheduler {
+    pub fn new(mq: Arc<dyn MessageQueue>, schedules: &[Schedule]) -> Result<Scheduler> {
+        if !schedules.is_empty() && !mq.shares_values() {
+            log::warn!("this backend keeps the last tick of `[[schedules]]` in the Dispatcher, so only one Dispatcher may run them, and ticks missed before it starts aren't caught up");
+        }
+        let producer = Producer::new(mq.clone());
+        let schedules = schedules.iter()
+            .map(|schedule| Ok((schedule.clone(), Self::parse(schedule)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Scheduler { mq, producer, schedules })
+    }
+
+    fn parse(schedule: &Schedule) -> Result<cron::Schedule> {
+        if schedule.name.is_empty() || !schedule.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            bail!("invalid schedule name `{}`, which may only contain letters, digits, `-` and `_`", schedule.name);
+        }
+        // The cron crate wants seconds, which conventional five-field expressions leave out
+        let expression = match schedule.cron.split_whitespace().count() {
+            5 => format!("0 {}", schedule.cron),
+            _ => schedule.cron.clone(),
+        };
+        cron::Schedule::from_str(&expression).context(format!("invalid cron expression `{}` for schedule `{}`", schedule.cron, schedule.name))
+    }
+
+    /// Runs each schedule in its own task
+    pub fn spawn(self) {
+        let this = Arc::new(self);
+        for index in 0..this.schedules.len() {
+            let this = this.clone();
+            tokio::spawn(async move { this.run(index).await });
+        }
+    }
+
+    async fn run(&self, index: usize) {
+        let (schedule, cron) = &self.schedules[index];
+        loop {
+            let wait = match self.tick(schedule, cron).await {
+                Ok(next) => (next - Utc::now()).to_std().unwrap_or_default(),
+                Err(e) => {
+                    log::error!("schedule:<{}> failed: {:?}", schedule.name, e);
+                    ERROR_BACKOFF
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Publishes the schedule's due ticks, unless another Dispatcher already has, and returns
+    /// when the next tick is due
+    async fn tick(&self, schedule: &Schedule, cron: &cron::Schedule) -> Result<DateTime<Utc>> {
+        let key = format!("schedule.{}", schedule.name);
+        let now = Utc::now();
+        let next = cron.after(&now).next().context(format!("schedule `{}` has no more ticks", schedule.name))?;
+
+        let last = match self.mq.get_value(&key).await? {
+            Some(last) => last,
+            None => {
+                // Never run before, so nothing was missed
+                self.mq.compare_and_set(&key, None, &now.to_rfc3339()).await?;
+                return Ok(next);
+            }
+        };
+        let last_tick = DateTime::parse_from_rfc3339(&last)
+            .context(format!("invalid last tick `{}` for schedule `{}`", last, schedule.name))?
+            .with_timezone(&Utc);
+        // Ticks fall on whole seconds, and stepping back from now skips one earlier in this second
+        let newest = cron.after(&(now - chrono::Duration::seconds(1))).next()
+            .filter(|tick| *tick <= now)
+            .or_else(|| cron.after(&now).next_back());
+        let newest = match newest {
+            Some(newest) if newest > last_tick => newest,
+            _ => return Ok(next),
+        };
+
+        let ticks = match schedule.catch_up {
+            CatchUp::All => cron.after(&last_tick).take_while(|tick| *tick <= newest).take(MAX_CATCH_UP).collect(),
+            CatchUp::Latest => vec![newest],
+            CatchUp::None if (now - newest).to_std().unwrap_or_default() <= MISSED_AFTER => vec![newest],
+            CatchUp::None => vec![],
+        };
+        if !self.mq.compare_and_set(&key, Some(&last), &newest.to_rfc3339()).await? {
+            // Another Dispatcher ran this tick
+            return Ok(next);
+        }
+        if ticks.last() != Some(&newest) {
+            log::warn!("schedule:<{}> skipped missed ticks up to {}", schedule.name, newest.to_rfc3339());
+        }
+        // What the last tick goes back to if publishing fails, so the unpublished ticks are tried again
+        let mut published = last;
+        for tick in ticks {
+            let msg = DynamicTaskMessage {
+                headers: HashMap::from([
+                    ("schedule".to_owned(), schedule.name.clone()),
+                    ("scheduled-at".to_owned(), tick.to_rfc3339()),
+                ]),
+                ..DynamicTaskMessage::new(schedule.type_name.clone(), schedule.task.clone())
+            };
+            if let Err(e) = self.producer.send(&msg).await {
+                self.mq.compare_and_set(&key, Some(&newest.to_rfc3339()), &published).await?;
+                return Err(e);
+            }
+            published = tick.to_rfc3339();
+            log::info!("schedule:<{}> tick:<{}> published task:<{}>", schedule.name, tick.to_rfc3339(), schedule.type_name);
+        }
+        Ok(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use anyhow::{bail, Result};
+    use async_trait::async_trait;
+    use chrono::{DurationRound, Utc};
+
+    use crate::config::{CatchUp, Schedule};
+    use crate::data::DynamicTaskMessage;
+    use crate::mq::{MemoryQueue, MessageQueue, Outgoing, Received};
+
+    use super::Scheduler;
+
+    /// A memory queue that fails to publish once it has published `publishes` messages
+    struct FailingQueue {
+        mq: MemoryQueue,
+        publishes: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl MessageQueue for FailingQueue {
+        async fn receive(&self) -> Result<Option<Received>> { self.mq.receive().await }
+        async fn ack(&self, msg: &Received) -> Result<()> { self.mq.ack(msg).await }
+        async fn nack(&self, msg: &Received) -> Result<()> { self.mq.nack(msg).await }
+        async fn publish_at(&self, topic: &str, msg: Outgoing, deliver_at: SystemTime) -> Result<()> { self.mq.publish_at(topic, msg, deliver_at).await }
+        async fn get_value(&self, key: &str) -> Result<Option<String>> { self.mq.get_value(key).await }
+        async fn compare_and_set(&self, key: &str, expected: Option<&str>, value: &str) -> Result<bool> { self.mq.compare_and_set(key, expected, value).await }
+        async fn delete_value(&self, key: &str) -> Result<()> { self.mq.delete_value(key).await }
+
+        async fn publish(&self, topic: &str, msg: Outgoing) -> Result<()> {
+            if self.publishes.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_err() {
+                bail!("publishing failed");
+            }
+            self.mq.publish(topic, msg).await
+        }
+    }
+
+    #[tokio::test]
+    async fn ticks_that_fail_to_publish_are_published_next_time() {
+        let schedule = Schedule {
+            name: "every-second".to_owned(),
+            cron: "* * * * * *".to_owned(),
+            type_name: "task".to_owned(),
+            task: serde_json::json!({}),
+            catch_up: CatchUp::All,
+        };
+        let queue = Arc::new(FailingQueue { mq: MemoryQueue::new(&["task".to_owned()]), publishes: AtomicUsize::new(2) });
+        let last = Utc::now().duration_trunc(chrono::Duration::seconds(1)).unwrap() - chrono::Duration::seconds(5);
+        queue.compare_and_set("schedule.every-second", None, &last.to_rfc3339()).await.unwrap();
+        let scheduler = Scheduler::new(queue.clone(), std::slice::from_ref(&schedule)).unwrap();
+        let (_, cron) = &scheduler.schedules[0];
+
+        assert!(scheduler.tick(&schedule, cron).await.is_err());
+        let second_tick = last + chrono::Duration::seconds(2);
+        assert_eq!(queue.get_value("schedule.every-second").await.unwrap(), Some(second_tick.to_rfc3339()));
+
+        queue.publishes.store(usize::MAX, Ordering::SeqCst);
+        scheduler.tick(&schedule, cron).await.unwrap();
+        let published: Vec<String> = queue.mq.waiting("task").iter()
+            .map(|payload| serde_json::from_slice::<DynamicTaskMessage>(payload).unwrap().headers["scheduled-at"].clone())
+            .collect();
+        assert!(published.len() >= 5);
+        assert_eq!(published.iter().collect::<HashSet<_>>().len(), published.len(), "a tick was published twice");
+    }
+}
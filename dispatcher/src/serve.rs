@@ -1,28 +1,42 @@
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use axum::{extract::{Path, State}, routing::{get, post}, Json, Router};
-use pulsar::TokioExecutor;
+use axum::{extract::{Path, Query, State}, routing::{get, post}, Json, Router};
+use chrono::{DateTime, Utc};
 use reqwest::StatusCode;
+use serde::Deserialize;
 use serde_json::{json, Value};
-use tokio::{sync::Mutex, task::JoinHandle};
+use tokio::task::JoinHandle;
 
-use crate::{data::DynamicTaskMessage, producer::Producer};
+use crate::{callback::Callbacks, core::Breaker, data::DynamicTaskMessage, producer::Producer};
 
 /// If enabled, HTTP endpoints served directly from the Dispatcher
 
 #[derive(Clone)]
 struct AppState {
-    producer: Arc<Mutex<Producer<TokioExecutor>>>
+    producer: Producer,
+    callbacks: Callbacks,
+    breakers: Arc<[Arc<Breaker>]>,
+}
+
+/// When to deliver a task submitted to `/tasks/:type/submit`, from its query string
+#[derive(Deserialize)]
+struct Schedule {
+    #[serde(default, with = "crate::data::rfc3339::option")]
+    deliver_at: Option<SystemTime>,
+    #[serde(default, with = "humantime_serde")]
+    delay: Option<Duration>,
 }
 
 pub struct Serve {
-    submit_producer: Arc<Mutex<Producer<TokioExecutor>>>
+    submit_producer: Producer,
+    callbacks: Callbacks,
+    breakers: Arc<[Arc<Breaker>]>,
 }
 
 impl Serve {
-    pub fn new(submit_producer: Producer<TokioExecutor>) -> Serve {
-        let submit_producer = Arc::new(Mutex::new(submit_producer));
-        Serve { submit_producer }
+    pub fn new(submit_producer: Producer, callbacks: Callbacks, breakers: Vec<Arc<Breaker>>) -> Serve {
+        Serve { submit_producer, callbacks, breakers: breakers.into() }
     }
 
     pub async fn spawn_start(self) -> JoinHandle<()> {
@@ -30,12 +44,15 @@ impl Serve {
     }
 
     pub async fn start(&self) {
-        let state = AppState { producer: self.submit_producer.clone() };
+        let state = AppState { producer: self.submit_producer.clone(), callbacks: self.callbacks.clone(), breakers: self.breakers.clone() };
         // build our application with a single route
         let app = Router::new()
             .route("/", get(|| async { "Hello, World!" }))
             .route("/tasks/submit_raw", post(Self::submit_dynamic_task))
             .route("/tasks/:type/submit", post(Self::submit_task))
+            .route("/callbacks/:token", post(Self::callback))
+            .route("/callbacks/:token/heartbeat", post(Self::heartbeat))
+            .route("/admin/circuit-breakers", get(Self::circuit_breakers))
             .with_state(state);
 
         // run our app with hyper, listening globally on port 2000
@@ -43,9 +60,28 @@ impl Serve {
         axum::serve(listener, app).await.unwrap();
     }
 
+    /// Takes a whole task envelope. Responds with 400 if it carries a `callback`, which only the
+    /// Dispatcher may set, since it would pass for a worker's response.
     async fn submit_dynamic_task(State(app_state): State<AppState>, Json(msg): Json<DynamicTaskMessage>) -> std::result::Result<Json<Value>, StatusCode> {
-        let mut producer = app_state.producer.lock().await;
-        producer.send(&msg).await.map_err(|_| { StatusCode::INTERNAL_SERVER_ERROR })?;
+        if msg.callback.is_some() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        app_state.producer.send(&msg).await.map_err(|_| { StatusCode::INTERNAL_SERVER_ERROR })?;
+        let result = json![
+            {
+                "successful": true
+            }
+        ];
+        Ok(Json::from(result))
+    }
+
+    async fn submit_task(State(app_state): State<AppState>, Path(type_name): Path<String>, Query(schedule): Query<Schedule>, Json(task): Json<Value>) -> std::result::Result<Json<Value>, StatusCode> {
+        let msg = DynamicTaskMessage {
+            deliver_at: schedule.deliver_at,
+            delay: schedule.delay,
+            ..DynamicTaskMessage::new(type_name, task)
+        };
+        app_state.producer.send(&msg).await.map_err(|_| { StatusCode::INTERNAL_SERVER_ERROR })?;
         let result = json![
             {
                 "successful": true
@@ -54,10 +90,16 @@ impl Serve {
         Ok(Json::from(result))
     }
 
-    async fn submit_task(State(app_state): State<AppState>, Path(type_name): Path<String>, Json(task): Json<Value>) -> std::result::Result<Json<Value>, StatusCode> {
-        let msg = DynamicTaskMessage { type_name, task };
-        let mut producer = app_state.producer.lock().await;
-        producer.send(&msg).await.map_err(|_| { StatusCode::INTERNAL_SERVER_ERROR })?;
+    /// Takes an asynchronous handler's response to a task it accepted. Responds with 404 if
+    /// the callback is unknown, has already arrived or has timed out.
+    async fn callback(State(app_state): State<AppState>, Path(token): Path<String>, Json(response): Json<Value>) -> std::result::Result<Json<Value>, StatusCode> {
+        let completed = app_state.callbacks.complete(&token, response).await.map_err(|e| {
+            log::error!("callback:<{}> could not be completed: {:?}", token, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        if !completed {
+            return Err(StatusCode::NOT_FOUND);
+        }
         let result = json![
             {
                 "successful": true
@@ -65,4 +107,28 @@ impl Serve {
         ];
         Ok(Json::from(result))
     }
-}
\ No newline at end of file
+
+    /// Extends the lease of a task an asynchronous handler is working on. Responds with 404 if
+    /// the task isn't waiting on that callback, e.g. because it has timed out, in which case the
+    /// worker should stop working on it.
+    async fn heartbeat(State(app_state): State<AppState>, Path(token): Path<String>) -> std::result::Result<Json<Value>, StatusCode> {
+        let lease_until = app_state.callbacks.heartbeat(&token).await.map_err(|e| {
+            log::error!("callback:<{}> could not be extended: {:?}", token, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let lease_until = lease_until.ok_or(StatusCode::NOT_FOUND)?;
+        let result = json![
+            {
+                "successful": true,
+                "lease_until": DateTime::<Utc>::from(lease_until).to_rfc3339()
+            }
+        ];
+        Ok(Json::from(result))
+    }
+
+    /// Lists the state of every endpoint's circuit breaker
+    async fn circuit_breakers(State(app_state): State<AppState>) -> Json<Value> {
+        let breakers: Vec<_> = app_state.breakers.iter().map(|breaker| breaker.status()).collect();
+        Json::from(json![{ "circuit_breakers": breakers }])
+    }
+}
@@ -1,3 +1,5 @@
+mod signature;
+
 use erased_serde::serialize_trait_object;
 use serde::{Deserialize, Serialize};
 
@@ -158,6 +160,12 @@ async fn main() {
         .route("/send-email", post(send_email))
         .route("/record-send-result", post(record_send_result));
 
+    // Verify that requests come from the Dispatcher if signing keys are given
+    let app = match signature::Verifier::from_env("TRAMPOLINE_SIGNING_KEYS") {
+        Some(verifier) => app.layer(axum::middleware::from_fn_with_state(verifier, signature::verify_signature)),
+        None => app,
+    };
+
     // run our app with hyper, listening globally on port 3000
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
     axum::serve(listener, app).await.unwrap();
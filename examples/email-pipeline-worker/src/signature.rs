@@ -0,0 +1,166 @@
+//! Verifies the signatures the Dispatcher sends when a handler sets `[handlers.http.signing]`.
+//! Self-contained so that other workers can copy it.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const SIGNATURE_HEADER: &str = "Trampoline-Signature";
+const TIMESTAMP_HEADER: &str = "Trampoline-Timestamp";
+const KEY_ID_HEADER: &str = "Trampoline-Key-Id";
+
+/// Largest request body the middleware buffers to verify
+const MAX_BODY: usize = 10 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct Verifier {
+    /// Secrets by key ID. Keep the old key here while rotating until the Dispatcher has switched.
+    keys: Arc<HashMap<String, Vec<u8>>>,
+    /// How far a request's timestamp may be from now, which bounds how long a captured request
+    /// can be replayed
+    tolerance: Duration,
+}
+
+impl Verifier {
+    pub fn new(keys: HashMap<String, Vec<u8>>) -> Verifier {
+        Verifier { keys: Arc::new(keys), tolerance: Duration::from_secs(300) }
+    }
+
+    /// Reads keys from an environment variable in the form `id=secret,id=secret`, if it's set
+    pub fn from_env(name: &str) -> Option<Verifier> {
+        let value = std::env::var(name).ok()?;
+        let keys = value.split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(id, secret)| (id.trim().to_owned(), secret.trim().as_bytes().to_vec()))
+            .collect();
+        Some(Verifier::new(keys))
+    }
+
+    pub fn verify(&self, headers: &HeaderMap, body: &[u8]) -> Result<(), &'static str> {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok()).ok_or("missing signature header");
+        let (signature, timestamp, key_id) = (header(SIGNATURE_HEADER)?, header(TIMESTAMP_HEADER)?, header(KEY_ID_HEADER)?);
+
+        let signed_at = UNIX_EPOCH + Duration::from_secs(timestamp.parse().map_err(|_| "invalid timestamp")?);
+        let now = SystemTime::now();
+        let skew = now.duration_since(signed_at).or_else(|_| signed_at.duration_since(now)).unwrap_or_default();
+        if skew > self.tolerance {
+            return Err("timestamp too far from now");
+        }
+
+        let secret = self.keys.get(key_id).ok_or("unknown key id")?;
+        let signature = decode_hex(signature).ok_or("invalid signature")?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).map_err(|_| "invalid key")?;
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        // Compares in constant time
+        mac.verify_slice(&signature).map_err(|_| "signature mismatch")
+    }
+}
+
+/// None if `hex` isn't valid hex, including if it has an odd length
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Rejects requests without a valid signature, for use with `axum::middleware::from_fn_with_state`
+pub async fn verify_signature(State(verifier): State<Verifier>, request: Request, next: Next) -> Result<Response, StatusCode> {
+    let (parts, body) = request.into_parts();
+    let body = to_bytes(body, MAX_BODY).await.map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+    if let Err(reason) = verifier.verify(&parts.headers, &body) {
+        log::warn!("rejecting request to {}: {}", parts.uri, reason);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use axum::http::{HeaderMap, HeaderValue};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::{Verifier, KEY_ID_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+
+    const BODY: &[u8] = br#"{"to": "a@example.com"}"#;
+
+    /// Headers like the Dispatcher's for `body` signed with `secret` at `signed_at`
+    fn sign(key_id: &str, secret: &str, signed_at: SystemTime, body: &[u8]) -> HeaderMap {
+        let timestamp = signed_at.duration_since(UNIX_EPOCH).unwrap().as_secs().to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        let signature: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+        let mut headers = HeaderMap::new();
+        headers.insert(SIGNATURE_HEADER, HeaderValue::from_str(&signature).unwrap());
+        headers.insert(TIMESTAMP_HEADER, HeaderValue::from_str(&timestamp).unwrap());
+        headers.insert(KEY_ID_HEADER, HeaderValue::from_str(key_id).unwrap());
+        headers
+    }
+
+    /// A verifier that knows the old and new keys, as while rotating
+    fn verifier() -> Verifier {
+        Verifier::new(HashMap::from([
+            ("old".to_owned(), b"old-secret".to_vec()),
+            ("new".to_owned(), b"new-secret".to_vec()),
+        ]))
+    }
+
+    #[test]
+    fn accepts_signatures_from_any_known_key() {
+        let now = SystemTime::now();
+        assert_eq!(verifier().verify(&sign("old", "old-secret", now, BODY), BODY), Ok(()));
+        assert_eq!(verifier().verify(&sign("new", "new-secret", now, BODY), BODY), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unknown_or_mismatched_keys() {
+        let now = SystemTime::now();
+        assert_eq!(verifier().verify(&sign("retired", "old-secret", now, BODY), BODY), Err("unknown key id"));
+        assert_eq!(verifier().verify(&sign("new", "old-secret", now, BODY), BODY), Err("signature mismatch"));
+    }
+
+    #[test]
+    fn rejects_a_tampered_body() {
+        let headers = sign("new", "new-secret", SystemTime::now(), BODY);
+        assert_eq!(verifier().verify(&headers, br#"{"to": "b@example.com"}"#), Err("signature mismatch"));
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_the_tolerance() {
+        let now = SystemTime::now();
+        let minutes = |n: u64| Duration::from_secs(n * 60);
+        assert_eq!(verifier().verify(&sign("new", "new-secret", now - minutes(4), BODY), BODY), Ok(()));
+        assert_eq!(verifier().verify(&sign("new", "new-secret", now - minutes(6), BODY), BODY), Err("timestamp too far from now"));
+        assert_eq!(verifier().verify(&sign("new", "new-secret", now + minutes(6), BODY), BODY), Err("timestamp too far from now"));
+
+        // The timestamp is signed, so it can't be moved to within the tolerance
+        let mut headers = sign("new", "new-secret", now - minutes(6), BODY);
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap().as_secs().to_string();
+        headers.insert(TIMESTAMP_HEADER, HeaderValue::from_str(&timestamp).unwrap());
+        assert_eq!(verifier().verify(&headers, BODY), Err("signature mismatch"));
+    }
+
+    #[test]
+    fn rejects_missing_or_malformed_headers() {
+        let mut headers = sign("new", "new-secret", SystemTime::now(), BODY);
+        headers.insert(SIGNATURE_HEADER, HeaderValue::from_static("not-hex"));
+        assert_eq!(verifier().verify(&headers, BODY), Err("invalid signature"));
+        headers.remove(SIGNATURE_HEADER);
+        assert_eq!(verifier().verify(&headers, BODY), Err("missing signature header"));
+    }
+}